@@ -0,0 +1,12 @@
+fn main() {
+    // Features are additive, so something else in the build may turn simdsp back on.  pure-rust still wins, but the C++
+    // bridge is built and linked for nothing, which is exactly what pure-rust builds are trying to avoid.
+    if std::env::var_os("CARGO_FEATURE_SIMDSP").is_some()
+        && std::env::var_os("CARGO_FEATURE_PURE_RUST").is_some()
+    {
+        println!(
+            "cargo:warning=hwsurvey_client has both the simdsp and pure-rust features.  CPUs are detected in Rust, but \
+             the simdsp C++ bridge is still built.  Use --no-default-features --features pure-rust to skip it."
+        );
+    }
+}
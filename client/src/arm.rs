@@ -0,0 +1,110 @@
+//! ARM SIMD features, from what Linux reports in `/proc`.
+//!
+//! Neither simdsp nor `std::arch` reports everything we want on ARM, but the kernel lists the CPU's hwcaps in
+//! `/proc/cpuinfo`, and the SVE vector length in `/proc/sys/abi/sve_default_vector_length`.
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use hwsurvey_payloads::cpu::ArmCapabilities;
+
+/// The hwcaps of the first processor in `/proc/cpuinfo`.
+///
+/// Every processor lists the same ones, except on big.LITTLE machines where the kernel reports only what all of them
+/// have.
+fn parse_hwcaps(cpuinfo: &str) -> Option<Vec<&str>> {
+    cpuinfo.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        (key.trim() == "Features").then(|| value.split_whitespace().collect())
+    })
+}
+
+/// Parse `/proc/cpuinfo`.  The vector length isn't in there, so it's left as 0.
+pub(crate) fn parse_cpuinfo(cpuinfo: &str) -> ArmCapabilities {
+    let hwcaps = parse_hwcaps(cpuinfo).unwrap_or_default();
+    let has = |x: &str| hwcaps.contains(&x);
+
+    ArmCapabilities {
+        // aarch64 calls it asimd, 32-bit ARM calls it neon.
+        neon: has("asimd") || has("neon"),
+        asimd_dotprod: has("asimddp"),
+        fp16: has("asimdhp"),
+        sve: has("sve"),
+        sve2: has("sve2"),
+        sve_vector_length: 0,
+    }
+}
+
+/// Read ARM capabilities from `proc`, which is usually `/proc`.
+pub fn read_arm_capabilities(proc: &Path) -> Result<ArmCapabilities> {
+    let cpuinfo_path = proc.join("cpuinfo");
+    let cpuinfo = std::fs::read_to_string(&cpuinfo_path)
+        .with_context(|| format!("Unable to read {}", cpuinfo_path.display()))?;
+    // Other architectures have a cpuinfo too, but no hwcaps.
+    if parse_hwcaps(&cpuinfo).is_none() {
+        anyhow::bail!("No ARM hwcaps in {}", cpuinfo_path.display());
+    }
+    let mut caps = parse_cpuinfo(&cpuinfo);
+
+    if caps.sve {
+        // This is the length processes get unless they ask for another, in bytes.
+        let vl_path = proc.join("sys/abi/sve_default_vector_length");
+        let bytes: u64 = std::fs::read_to_string(&vl_path)
+            .with_context(|| format!("Unable to read {}", vl_path.display()))?
+            .trim()
+            .parse()
+            .with_context(|| format!("Unable to parse {}", vl_path.display()))?;
+        caps.sve_vector_length = bytes * 8;
+    }
+
+    Ok(caps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(machine: &str) -> ArmCapabilities {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/machines")
+            .join(machine)
+            .join("proc");
+        read_arm_capabilities(&root).unwrap()
+    }
+
+    /// (neon, asimd_dotprod, fp16, sve, sve2, sve_vector_length)
+    fn flags(c: &ArmCapabilities) -> (bool, bool, bool, bool, bool, u64) {
+        (
+            c.neon,
+            c.asimd_dotprod,
+            c.fp16,
+            c.sve,
+            c.sve2,
+            c.sve_vector_length,
+        )
+    }
+
+    #[test]
+    fn test_fixtures() {
+        for (machine, expected) in [
+            (
+                "raspberry_pi_3_armv7",
+                (true, false, false, false, false, 0),
+            ),
+            ("raspberry_pi_4", (true, false, false, false, false, 0)),
+            ("graviton2", (true, true, true, false, false, 0)),
+            ("graviton3", (true, true, true, true, false, 256)),
+            ("graviton4", (true, true, true, true, true, 128)),
+        ] {
+            assert_eq!(flags(&fixture(machine)), expected, "{}", machine);
+        }
+    }
+
+    #[test]
+    fn test_missing_features() {
+        assert_eq!(
+            flags(&parse_cpuinfo("processor\t: 0\n")),
+            (false, false, false, false, false, 0)
+        );
+    }
+}
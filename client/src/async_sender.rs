@@ -0,0 +1,232 @@
+//! Sending from async code, for applications which already run tokio.
+//!
+//! Unlike [crate::send_metrics], nothing happens in the background: the payload is only sent while the future is
+//! polled, and dropping the future stops sending, including any wait before a retry.
+use std::fmt;
+
+use reqwest::{Client, StatusCode, Url};
+
+use crate::build_payload::PayloadBuilder;
+use crate::sender::{compute_sleep, submit_url, REQUEST_TIMEOUT};
+
+/// A payload the server accepted.
+#[derive(Debug)]
+pub struct Sent {
+    /// How many attempts it took, including the one which worked.
+    pub attempts: u64,
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    /// The URL or `max_attempts` was bad, or the payload couldn't be built, so nothing was sent.
+    Payload(anyhow::Error),
+
+    /// The server refused the payload with a 4xx.  Sending it again would get the same answer, so we don't.
+    Rejected { status: StatusCode, body: String },
+
+    /// Every attempt failed, by not reaching the server or by the server failing with a 5xx.  Holds the last error.
+    GaveUp { attempts: u64, error: anyhow::Error },
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Payload(e) => write!(f, "Nothing was sent: {:#}", e),
+            SendError::Rejected { status, body } => {
+                write!(f, "The server rejected the payload: {} {}", status, body)
+            }
+            SendError::GaveUp { attempts, error } => {
+                write!(f, "Unable to send after {} attempts: {:#}", attempts, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SendError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SendError::Payload(e) | SendError::GaveUp { error: e, .. } => Some(e.as_ref()),
+            SendError::Rejected { .. } => None,
+        }
+    }
+}
+
+/// Why one attempt failed.
+enum AttemptError {
+    Retry(anyhow::Error),
+    Fatal(SendError),
+}
+
+async fn attempt_sending(client: &Client, url: &Url, body: &str) -> Result<(), AttemptError> {
+    let resp = client
+        .post(url.clone())
+        .body(body.to_string())
+        .timeout(REQUEST_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| AttemptError::Retry(e.into()))?;
+
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(());
+    }
+
+    let body = resp.text().await.unwrap_or_else(|e| {
+        log::warn!("Error reading body from server: {:?}", e);
+        String::from("Unable to print body")
+    });
+    let body = body.chars().take(1024).collect::<String>();
+
+    if status.is_server_error() {
+        Err(AttemptError::Retry(anyhow::anyhow!(
+            "Got {} from server: {}",
+            status.as_u16(),
+            body
+        )))
+    } else {
+        Err(AttemptError::Fatal(SendError::Rejected { status, body }))
+    }
+}
+
+/// Send a payload with every collector, trying up to `max_attempts` times.  `max_attempts` must be at least 1.
+pub async fn send(url: &str, token: &str, max_attempts: u64) -> Result<Sent, SendError> {
+    send_with(url, token, max_attempts, PayloadBuilder::new()).await
+}
+
+/// Like [send], but only with the collectors in `builder`.
+pub async fn send_with(
+    url: &str,
+    token: &str,
+    max_attempts: u64,
+    builder: PayloadBuilder,
+) -> Result<Sent, SendError> {
+    if max_attempts == 0 {
+        return Err(SendError::Payload(anyhow::anyhow!(
+            "max_attempts must be at least 1"
+        )));
+    }
+
+    let url = Url::parse(url)
+        .map_err(anyhow::Error::from)
+        .and_then(|u| submit_url(&u, token))
+        .map_err(SendError::Payload)?;
+
+    // Collectors read files and ask the OS, which would block the executor.
+    let payload = tokio::task::spawn_blocking(move || builder.build())
+        .await
+        .map_err(|e| SendError::Payload(e.into()))?
+        .map_err(SendError::Payload)?;
+    let body = serde_json::to_string(&payload).map_err(|e| SendError::Payload(e.into()))?;
+
+    let client = Client::new();
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let error = match attempt_sending(&client, &url, &body).await {
+            Ok(()) => {
+                log::info!("Sent metrics");
+                return Ok(Sent { attempts });
+            }
+            Err(AttemptError::Fatal(e)) => return Err(e),
+            Err(AttemptError::Retry(e)) => e,
+        };
+
+        if attempts >= max_attempts {
+            return Err(SendError::GaveUp { attempts, error });
+        }
+        log::warn!("Error sending metrics. Retrying. Got: {:?}", error);
+
+        let sleep =
+            compute_sleep(attempts).map_err(|error| SendError::GaveUp { attempts, error })?;
+        tokio::time::sleep(sleep).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::collector::OsCollector;
+
+    fn builder() -> PayloadBuilder {
+        PayloadBuilder::empty().with(OsCollector)
+    }
+
+    /// Accept one connection, and read the request line from it.
+    async fn accept(listener: &TcpListener) -> (TcpStream, String) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0; 4096];
+        let n = stream.read(&mut buf).await.unwrap();
+        let request = String::from_utf8_lossy(&buf[..n]);
+        let line = request.lines().next().unwrap().to_string();
+        (stream, line)
+    }
+
+    async fn listen() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        (listener, url)
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let (listener, url) = listen().await;
+        let server = tokio::spawn(async move {
+            for response in [
+                "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                "HTTP/1.1 400 Bad Request\r\nContent-Length: 8\r\nConnection: close\r\n\r\nBad JSON",
+            ] {
+                let (mut stream, line) = accept(&listener).await;
+                assert_eq!(line, "POST /submit/v2?token=abc HTTP/1.1");
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let sent = send_with(&url, "abc", 1, builder()).await.unwrap();
+        assert_eq!(sent.attempts, 1);
+
+        match send_with(&url, "abc", 5, builder()).await {
+            Err(SendError::Rejected { status, body }) => {
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+                assert_eq!(body, "Bad JSON");
+            }
+            x => panic!("Expected a rejection, got {:?}", x),
+        }
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_zero_attempts_is_an_error() {
+        let (listener, url) = listen().await;
+        assert!(matches!(
+            send_with(&url, "abc", 0, builder()).await,
+            Err(SendError::Payload(_))
+        ));
+
+        // Nothing should have tried to connect.
+        let accepted = tokio::time::timeout(Duration::from_millis(100), listener.accept()).await;
+        assert!(accepted.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cancellation_closes_the_connection() {
+        let (listener, url) = listen().await;
+        let send = send_with(&url, "abc", 1, builder());
+        let (result, (mut stream, _)) = tokio::join!(
+            tokio::time::timeout(Duration::from_millis(500), send),
+            accept(&listener)
+        );
+        assert!(
+            result.is_err(),
+            "The server never answers, so this should time out"
+        );
+
+        // Dropping the future should have hung up rather than leaving the request running.
+        let mut buf = [0; 1];
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    }
+}
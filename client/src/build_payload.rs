@@ -1,7 +1,9 @@
 use anyhow::Result;
 use sysinfo::{System, SystemExt};
 
-use hwsurvey_payloads::{memory::Memory, PayloadV1};
+use hwsurvey_payloads::PayloadV2;
+
+use crate::collector::{default_collectors, Collector, UNKNOWN};
 
 const SALT: &str = "98badb58-e077-11ec-8edf-00d8612ce6ed";
 
@@ -14,7 +16,7 @@ fn double_hash(input: &str) -> String {
     let level2 = {
         let mut hasher = Sha512::new();
         hasher.update(SALT.as_bytes());
-        hasher.update(&[0]);
+        hasher.update([0]);
         hasher.update(input);
         let res = hasher.finalize();
         hex::encode(&res[..])
@@ -25,43 +27,235 @@ fn double_hash(input: &str) -> String {
     let res = hasher.finalize();
     hex::encode(&res[..])
 }
-pub fn build_payload() -> Result<PayloadV1> {
-    let simdsp = hwsurvey_simdsp_bridger::get_system_info()?;
 
-    let sysinfo = System::new_with_specifics(sysinfo::RefreshKind::new().with_memory().with_cpu());
-    let memory = Memory {
-        // We want bytes, sysinfo gives us kb.
-        total: sysinfo.total_memory() * 1024,
-    };
+/// Where the machine ID comes from.  Tests replace it, so that the ID doesn't depend on the machine running them.
+pub trait Identity: Send {
+    /// The MAC address of one of the machine's network interfaces.
+    fn mac_address(&self) -> Result<[u8; 6]>;
+
+    /// `None` if we can't find one.
+    fn hostname(&self) -> Option<String>;
+}
+
+/// The identity of the machine we're running on.
+pub struct SystemIdentity;
 
-    let machine_id = {
-        let mac_address_raw = mac_address::get_mac_address()?
+impl Identity for SystemIdentity {
+    fn mac_address(&self) -> Result<[u8; 6]> {
+        let mac_address = mac_address::get_mac_address()?
             .ok_or_else(|| anyhow::anyhow!("Unable to get a MAC address"))?;
+        Ok(mac_address.bytes())
+    }
 
-        let mac_address = hex::encode(mac_address_raw.bytes());
+    fn hostname(&self) -> Option<String> {
+        System::new_with_specifics(sysinfo::RefreshKind::new()).host_name()
+    }
+}
 
-        let maybe_hostname = sysinfo.host_name();
-        let hostname = match maybe_hostname.as_ref() {
-            Some(x) => x.as_str(),
-            None => {
-                log::warn!("Unable to get hostname. Using ahrd-coded default");
-                "unknown"
-            }
+/// The machine ID: a hash of the MAC address and hostname, so that it stays the same across runs.
+fn machine_id(identity: &dyn Identity) -> Result<String> {
+    let mac_address = hex::encode(identity.mac_address()?);
+
+    let hostname = identity.hostname().unwrap_or_else(|| {
+        log::warn!("Unable to get hostname. Using hard-coded default");
+        UNKNOWN.to_string()
+    });
+
+    Ok(double_hash(&format!("{}\n{}", mac_address, hostname)))
+}
+
+/// Builds a payload by running a list of [Collector]s.
+///
+/// Only the machine ID is required.  If a collector fails, we log it and send the payload without that collector's
+/// part.
+pub struct PayloadBuilder {
+    identity: Box<dyn Identity>,
+    collectors: Vec<Box<dyn Collector>>,
+}
+
+impl Default for PayloadBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PayloadBuilder {
+    /// A builder with every collector.
+    pub fn new() -> PayloadBuilder {
+        PayloadBuilder {
+            identity: Box::new(SystemIdentity),
+            collectors: default_collectors(),
+        }
+    }
+
+    /// A builder with no collectors, for applications which want to pick them.
+    pub fn empty() -> PayloadBuilder {
+        PayloadBuilder {
+            identity: Box::new(SystemIdentity),
+            collectors: vec![],
+        }
+    }
+
+    /// Compute the machine ID from `identity` rather than this machine.
+    pub fn identity(mut self, identity: impl Identity + 'static) -> PayloadBuilder {
+        self.identity = Box::new(identity);
+        self
+    }
+
+    /// Add a collector, which runs after the others.
+    pub fn with(mut self, collector: impl Collector + 'static) -> PayloadBuilder {
+        self.collectors.push(Box::new(collector));
+        self
+    }
+
+    /// Remove the collectors with the given name.
+    pub fn without(mut self, name: &str) -> PayloadBuilder {
+        self.collectors.retain(|c| c.name() != name);
+        self
+    }
+
+    pub fn build(&self) -> Result<PayloadV2> {
+        let mut payload = PayloadV2 {
+            version: Default::default(),
+            machine_id: machine_id(self.identity.as_ref())?,
+            os: UNKNOWN.to_string(),
+            cpu: None,
+            memory: None,
         };
 
-        double_hash(&format!("{}\n{}", mac_address, hostname))
-    };
-    let os = std::env::consts::OS.to_string();
-
-    Ok(PayloadV1 {
-        simdsp,
-        memory,
-        os,
-        machine_id,
-    })
+        for collector in self.collectors.iter() {
+            if let Err(e) = collector.collect(&mut payload) {
+                log::warn!(
+                    "Unable to collect {}. Sending without it: {:?}",
+                    collector.name(),
+                    e
+                );
+            }
+        }
+
+        Ok(payload)
+    }
+}
+
+/// Build a payload with every collector.
+pub fn build_payload() -> Result<PayloadV2> {
+    PayloadBuilder::new().build()
 }
 
-#[test]
-fn test_payload_building() {
-    build_payload().expect("Should be able to build the payload");
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::collector::{ArmCollector, CacheCollector, MemoryCollector, X86Collector};
+
+    /// Recordings of real machines, one per directory.
+    fn fixtures() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/machines")
+    }
+
+    #[test]
+    fn test_payload_building() {
+        build_payload().expect("Should be able to build the payload");
+    }
+
+    #[test]
+    fn test_failed_collector_gives_partial_payload() {
+        struct Failing;
+
+        impl Collector for Failing {
+            fn name(&self) -> &'static str {
+                "failing"
+            }
+
+            fn collect(&self, _payload: &mut PayloadV2) -> Result<()> {
+                anyhow::bail!("No hardware here")
+            }
+        }
+
+        // Collectors after the one which failed still run.
+        let dir = fixtures().join("graviton3");
+        let payload = PayloadBuilder::empty()
+            .identity(FixtureIdentity(dir.clone()))
+            .with(CacheCollector::with_sysfs(dir.join("sys")))
+            .with(Failing)
+            .with(MemoryCollector::with_proc(dir.join("proc")))
+            .build()
+            .unwrap();
+        assert_eq!(payload.os, UNKNOWN);
+        assert_eq!(payload.memory.unwrap().total, 16466366464);
+        let cpu = payload.cpu.unwrap();
+        assert_eq!(cpu.caches.unwrap().l2u, 1048576);
+        assert!(cpu.capabilities.is_none());
+        assert!(cpu.arm_capabilities.is_none());
+
+        // Only the machine ID is required.
+        let payload = PayloadBuilder::empty()
+            .identity(FixtureIdentity(dir))
+            .with(Failing)
+            .build()
+            .unwrap();
+        assert!(payload.cpu.is_none());
+        assert!(payload.memory.is_none());
+    }
+
+    /// The MAC address and hostname of a machine in `fixtures/machines`.
+    struct FixtureIdentity(PathBuf);
+
+    impl Identity for FixtureIdentity {
+        fn mac_address(&self) -> Result<[u8; 6]> {
+            let mac = std::fs::read_to_string(self.0.join("mac"))?;
+            let bytes = hex::decode(mac.trim().replace(':', ""))?;
+            Ok(bytes.as_slice().try_into()?)
+        }
+
+        fn hostname(&self) -> Option<String> {
+            let hostname = std::fs::read_to_string(self.0.join("hostname")).ok()?;
+            Some(hostname.trim().to_string())
+        }
+    }
+
+    /// Build a payload from everything recorded about a machine, which is everything but the OS.  The ARM and x86
+    /// collectors each fail on the other's machines, leaving their sections out.
+    fn build_fixture(dir: &Path) -> PayloadV2 {
+        PayloadBuilder::empty()
+            .identity(FixtureIdentity(dir.to_path_buf()))
+            .with(CacheCollector::with_sysfs(dir.join("sys")))
+            .with(ArmCollector::with_proc(dir.join("proc")))
+            .with(X86Collector::with_proc(dir.join("proc")))
+            .with(MemoryCollector::with_proc(dir.join("proc")))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_fixtures() {
+        let mut machines = std::fs::read_dir(fixtures())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect::<Vec<_>>();
+        machines.sort();
+        assert!(!machines.is_empty());
+
+        for dir in machines {
+            let expected: serde_json::Value =
+                serde_json::from_str(&std::fs::read_to_string(dir.join("expected.json")).unwrap())
+                    .unwrap();
+            assert_eq!(
+                serde_json::to_value(build_fixture(&dir)).unwrap(),
+                expected,
+                "{}",
+                dir.display()
+            );
+        }
+    }
+
+    /// Changing the hash would give every machine a new ID, so check it against one worked out elsewhere.
+    #[test]
+    fn test_double_hash() {
+        assert_eq!(
+            double_hash("00d8612ce6ed\nexample"),
+            "ee25778b17eb39252b9ea519a6144fb5ed832d8596e9b6ea3a915507dbb3ed457bfecd8e7ad01abc04460915ae09a6f8689358359223288c64ee6f7d92fccc0a"
+        );
+    }
 }
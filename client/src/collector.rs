@@ -0,0 +1,277 @@
+//! Probes which each fill in part of a payload.
+//!
+//! A probe which fails only loses its own part: the payload is still sent with whatever the others found.
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use sysinfo::{System, SystemExt};
+
+use hwsurvey_payloads::cpu::Cpu;
+use hwsurvey_payloads::memory::Memory;
+use hwsurvey_payloads::PayloadV2;
+
+/// What we send for names, like the OS, which we couldn't work out.  The server knows it.
+pub const UNKNOWN: &str = "unknown";
+
+pub trait Collector: Send {
+    /// Identifies the collector in logs, and to [crate::PayloadBuilder::without].
+    fn name(&self) -> &'static str;
+
+    /// Fill in this collector's part of `payload`.
+    ///
+    /// On error, the payload is sent without this collector's part, so collectors should only write to it once they
+    /// have everything.
+    fn collect(&self, payload: &mut PayloadV2) -> Result<()>;
+}
+
+/// The CPU section, which several collectors contribute to.  Whichever runs first creates it with unknown names.
+fn cpu_section(payload: &mut PayloadV2) -> &mut Cpu {
+    payload.cpu.get_or_insert_with(|| Cpu {
+        manufacturer: UNKNOWN.to_string(),
+        architecture: UNKNOWN.to_string(),
+        caches: None,
+        capabilities: None,
+        arm_capabilities: None,
+    })
+}
+
+/// The CPU's manufacturer, architecture, caches, and x86 features, from simdsp or the pure-Rust backend depending on
+/// features.
+pub struct CpuCollector;
+
+impl Collector for CpuCollector {
+    fn name(&self) -> &'static str {
+        "cpu"
+    }
+
+    #[cfg(feature = "pure-rust")]
+    fn collect(&self, payload: &mut PayloadV2) -> Result<()> {
+        // No caches here: the cache collector adds them where it can, and otherwise they're left out rather than sent
+        // as 0.
+        let info = crate::cpu_info::get_cpu_info();
+        let cpu = cpu_section(payload);
+        cpu.manufacturer = info.manufacturer.to_string();
+        cpu.architecture = info.architecture.to_string();
+        cpu.capabilities = info.capabilities;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "pure-rust"))]
+    fn collect(&self, payload: &mut PayloadV2) -> Result<()> {
+        let info = hwsurvey_simdsp_bridger::get_system_info()?;
+        let cpu = cpu_section(payload);
+        cpu.manufacturer = info.cpu_manufacturer;
+        cpu.architecture = info.cpu_architecture;
+        cpu.caches = Some(info.cache_info);
+        cpu.capabilities = Some(info.cpu_capabilities);
+        Ok(())
+    }
+}
+
+/// ARM SIMD features, from `/proc`.
+pub struct ArmCollector {
+    proc: PathBuf,
+}
+
+impl ArmCollector {
+    pub fn new() -> ArmCollector {
+        ArmCollector::with_proc("/proc")
+    }
+
+    /// Read from a directory laid out like `/proc`, e.g. one recorded from another machine.
+    pub fn with_proc(proc: impl Into<PathBuf>) -> ArmCollector {
+        ArmCollector { proc: proc.into() }
+    }
+}
+
+impl Default for ArmCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Collector for ArmCollector {
+    fn name(&self) -> &'static str {
+        "arm"
+    }
+
+    fn collect(&self, payload: &mut PayloadV2) -> Result<()> {
+        let caps = crate::arm::read_arm_capabilities(&self.proc)?;
+        cpu_section(payload).arm_capabilities = Some(caps);
+        Ok(())
+    }
+}
+
+/// The manufacturer and x86 features, from `/proc`.
+///
+/// Not one of the [default_collectors], since the CPU collector asks the CPU itself, but unlike it this can read
+/// recordings of other machines.
+pub struct X86Collector {
+    proc: PathBuf,
+}
+
+impl X86Collector {
+    pub fn new() -> X86Collector {
+        X86Collector::with_proc("/proc")
+    }
+
+    /// Read from a directory laid out like `/proc`, e.g. one recorded from another machine.
+    pub fn with_proc(proc: impl Into<PathBuf>) -> X86Collector {
+        X86Collector { proc: proc.into() }
+    }
+}
+
+impl Default for X86Collector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Collector for X86Collector {
+    fn name(&self) -> &'static str {
+        "x86"
+    }
+
+    fn collect(&self, payload: &mut PayloadV2) -> Result<()> {
+        let (manufacturer, capabilities) = crate::x86::read_x86_cpu(&self.proc)?;
+        let cpu = cpu_section(payload);
+        cpu.manufacturer = manufacturer.to_string();
+        cpu.architecture = "x86".to_string();
+        cpu.capabilities = Some(capabilities);
+        Ok(())
+    }
+}
+
+/// Cache sizes, from sysfs.  Replaces any the CPU collector found; the pure-Rust backend finds none itself.
+pub struct CacheCollector {
+    sysfs: PathBuf,
+}
+
+impl CacheCollector {
+    pub fn new() -> CacheCollector {
+        CacheCollector::with_sysfs("/sys")
+    }
+
+    /// Read from a directory laid out like `/sys`.
+    pub fn with_sysfs(sysfs: impl Into<PathBuf>) -> CacheCollector {
+        CacheCollector {
+            sysfs: sysfs.into(),
+        }
+    }
+}
+
+impl Default for CacheCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Collector for CacheCollector {
+    fn name(&self) -> &'static str {
+        "caches"
+    }
+
+    fn collect(&self, payload: &mut PayloadV2) -> Result<()> {
+        let caches = crate::sysfs::read_caches(&self.sysfs)?;
+        cpu_section(payload).caches = Some(caches);
+        Ok(())
+    }
+}
+
+/// `MemTotal` from a file laid out like `/proc/meminfo`, in bytes.
+fn read_mem_total(meminfo: &Path) -> Result<u64> {
+    let contents = std::fs::read_to_string(meminfo)
+        .with_context(|| format!("Unable to read {}", meminfo.display()))?;
+    let kb: u64 = contents
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))
+        .and_then(|rest| rest.trim().strip_suffix("kB"))
+        .ok_or_else(|| anyhow::anyhow!("No MemTotal in {}", meminfo.display()))?
+        .trim()
+        .parse()
+        .with_context(|| format!("Unable to parse MemTotal in {}", meminfo.display()))?;
+    Ok(kb * 1024)
+}
+
+/// Total memory.
+pub struct MemoryCollector {
+    /// If set, read `meminfo` from here rather than asking sysinfo.
+    proc: Option<PathBuf>,
+}
+
+impl MemoryCollector {
+    pub fn new() -> MemoryCollector {
+        MemoryCollector { proc: None }
+    }
+
+    /// Read from a directory laid out like `/proc`.
+    pub fn with_proc(proc: impl Into<PathBuf>) -> MemoryCollector {
+        MemoryCollector {
+            proc: Some(proc.into()),
+        }
+    }
+}
+
+impl Default for MemoryCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Collector for MemoryCollector {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn collect(&self, payload: &mut PayloadV2) -> Result<()> {
+        let total = match &self.proc {
+            Some(proc) => read_mem_total(&proc.join("meminfo"))?,
+            None => {
+                let sysinfo = System::new_with_specifics(sysinfo::RefreshKind::new().with_memory());
+                // We want bytes, sysinfo gives us kb.
+                sysinfo.total_memory() * 1024
+            }
+        };
+        if total == 0 {
+            anyhow::bail!("Unable to get total memory");
+        }
+
+        payload.memory = Some(Memory { total });
+        Ok(())
+    }
+}
+
+/// The kind of OS, e.g. windows, but not windows 10.
+pub struct OsCollector;
+
+impl Collector for OsCollector {
+    fn name(&self) -> &'static str {
+        "os"
+    }
+
+    fn collect(&self, payload: &mut PayloadV2) -> Result<()> {
+        payload.os = std::env::consts::OS.to_string();
+        Ok(())
+    }
+}
+
+/// Every collector which works on this platform, in the order they run by default.
+pub fn default_collectors() -> Vec<Box<dyn Collector>> {
+    let mut collectors: Vec<Box<dyn Collector>> =
+        vec![Box::new(OsCollector), Box::new(CpuCollector)];
+
+    // simdsp finds the caches itself.
+    if cfg!(all(feature = "pure-rust", target_os = "linux")) {
+        collectors.push(Box::new(CacheCollector::new()));
+    }
+
+    if cfg!(all(
+        target_os = "linux",
+        any(target_arch = "aarch64", target_arch = "arm")
+    )) {
+        collectors.push(Box::new(ArmCollector::new()));
+    }
+
+    collectors.push(Box::new(MemoryCollector::new()));
+    collectors
+}
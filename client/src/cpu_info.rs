@@ -0,0 +1,110 @@
+//! Pure-Rust CPU detection, for builds without the simdsp C++ bridge.
+//!
+//! Finds what simdsp would, with features from `std::arch`, except the caches: on Linux,
+//! [crate::collector::CacheCollector] reads those from sysfs.
+use hwsurvey_payloads::simdsp_bridger::CpuCapabilities;
+
+/// What the pure-Rust backend knows about the CPU.
+pub struct CpuInfo {
+    pub manufacturer: &'static str,
+    pub architecture: &'static str,
+    /// Only on x86, which is all [CpuCapabilities] describes.
+    pub capabilities: Option<CpuCapabilities>,
+}
+
+fn architecture() -> &'static str {
+    if cfg!(any(target_arch = "x86", target_arch = "x86_64")) {
+        "x86"
+    } else if cfg!(target_arch = "aarch64") {
+        "aarch64"
+    } else {
+        "unknown"
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::{__cpuid, CpuidResult};
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::{__cpuid, CpuidResult};
+
+    use hwsurvey_payloads::simdsp_bridger::CpuCapabilities;
+
+    fn cpuid(leaf: u32) -> CpuidResult {
+        // Every x86 CPU Rust supports has cpuid.  Newer Rust considers this safe.
+        #[allow(unused_unsafe)]
+        unsafe {
+            __cpuid(leaf)
+        }
+    }
+
+    pub fn manufacturer() -> &'static str {
+        let r = cpuid(0);
+        let mut vendor = [0u8; 12];
+        vendor[0..4].copy_from_slice(&r.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&r.edx.to_le_bytes());
+        vendor[8..12].copy_from_slice(&r.ecx.to_le_bytes());
+        crate::x86::manufacturer(std::str::from_utf8(&vendor).unwrap_or_default())
+    }
+
+    pub fn capabilities() -> CpuCapabilities {
+        // std doesn't detect AMD's FMA4 and XOP, so ask cpuid ourselves.  Both use the AVX registers, so they're only
+        // usable if the OS saves those, which std checks for AVX.
+        let extended = if cpuid(0x8000_0000).eax >= 0x8000_0001 {
+            cpuid(0x8000_0001).ecx
+        } else {
+            0
+        };
+        let avx = is_x86_feature_detected!("avx");
+
+        CpuCapabilities {
+            x86_sse2: is_x86_feature_detected!("sse2"),
+            x86_sse3: is_x86_feature_detected!("sse3"),
+            x86_ssse3: is_x86_feature_detected!("ssse3"),
+            x86_sse4_1: is_x86_feature_detected!("sse4.1"),
+            x86_popcnt_insn: is_x86_feature_detected!("popcnt"),
+            x86_avx: avx,
+            x86_avx2: is_x86_feature_detected!("avx2"),
+            x86_fma3: is_x86_feature_detected!("fma"),
+            x86_fma4: avx && extended & (1 << 16) != 0,
+            x86_xop: avx && extended & (1 << 11) != 0,
+            x86_avx512f: is_x86_feature_detected!("avx512f"),
+            x86_avx512bw: is_x86_feature_detected!("avx512bw"),
+            x86_avx512dq: is_x86_feature_detected!("avx512dq"),
+            x86_avx512vl: is_x86_feature_detected!("avx512vl"),
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn manufacturer() -> &'static str {
+    x86::manufacturer()
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn manufacturer() -> &'static str {
+    if cfg!(all(target_arch = "aarch64", target_os = "macos")) {
+        "apple"
+    } else {
+        "unknown"
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn capabilities() -> Option<CpuCapabilities> {
+    Some(x86::capabilities())
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn capabilities() -> Option<CpuCapabilities> {
+    None
+}
+
+pub fn get_cpu_info() -> CpuInfo {
+    CpuInfo {
+        manufacturer: manufacturer(),
+        architecture: architecture(),
+        capabilities: capabilities(),
+    }
+}
@@ -1,4 +1,19 @@
+pub mod arm;
+#[cfg(feature = "async")]
+mod async_sender;
 pub mod build_payload;
+pub mod collector;
+#[cfg(feature = "pure-rust")]
+mod cpu_info;
 mod sender;
+mod sysfs;
+pub mod x86;
 
-pub use sender::{send_metrics, send_synchronously};
+#[cfg(not(any(feature = "simdsp", feature = "pure-rust")))]
+compile_error!("Enable either the simdsp or the pure-rust feature to detect CPUs");
+
+#[cfg(feature = "async")]
+pub use async_sender::{send, send_with, SendError, Sent};
+pub use build_payload::PayloadBuilder;
+pub use collector::Collector;
+pub use sender::{send_metrics, send_metrics_with, send_synchronously, send_synchronously_with};
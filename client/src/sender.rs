@@ -4,15 +4,17 @@ use anyhow::Result;
 use rand::prelude::*;
 use reqwest::blocking::Client;
 
+use crate::build_payload::PayloadBuilder;
+
 const RETRY_DUR: Duration = Duration::from_secs(30);
 const JITTER: Duration = Duration::from_secs(20);
-const MAX_ATTEMPTS: u64 = 5;
+pub(crate) const MAX_ATTEMPTS: u64 = 5;
 
 /// Where are we going?
-const SUBPATH: &str = "/submit/v1";
+const SUBPATH: &str = "/submit/v2";
 
 /// This should be large because we're going to be running in one GCP region for all over the world.
-const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+pub(crate) const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 
 fn check_f64_for_duration(input: f64) -> Result<()> {
     if input < 0.0 || !input.is_finite() {
@@ -29,23 +31,28 @@ fn compute_jitter() -> Result<Duration> {
     Ok(Duration::from_secs_f64(candidate))
 }
 
-fn compute_sleep(attempts: u64) -> Result<Duration> {
+pub(crate) fn compute_sleep(attempts: u64) -> Result<Duration> {
     let base = RETRY_DUR + compute_jitter()?;
     let candidate = base.as_secs_f64().powi(attempts as i32);
     check_f64_for_duration(candidate)?;
     Ok(Duration::from_secs_f64(candidate))
 }
 
+/// Where to send payloads for the application with `token`, given the server's base URL.
+pub(crate) fn submit_url(url: &reqwest::Url, token: &str) -> Result<reqwest::Url> {
+    let mut url = url.join(SUBPATH)?;
+    url.query_pairs_mut().append_pair("token", token);
+    Ok(url)
+}
+
 fn attempt_sending(
     client: &Client,
     url: &reqwest::Url,
     token: &str,
-    payload: &hwsurvey_payloads::PayloadV1,
+    payload: &hwsurvey_payloads::PayloadV2,
 ) -> Result<()> {
     let serialized = serde_json::to_string(payload)?;
-
-    let mut url = url.join(SUBPATH)?;
-    url.query_pairs_mut().append_pair("token", token);
+    let url = submit_url(url, token)?;
 
     let resp = client
         .post(url)
@@ -71,9 +78,14 @@ fn attempt_sending(
     Ok(())
 }
 
-fn sending_thread_fallible(url: String, token: String, max_attempts: u64) -> Result<()> {
+fn sending_thread_fallible(
+    url: String,
+    token: String,
+    max_attempts: u64,
+    builder: &PayloadBuilder,
+) -> Result<()> {
     let url = reqwest::Url::parse(&url)?;
-    let payload = crate::build_payload::build_payload()?;
+    let payload = builder.build()?;
     let client = Client::new();
 
     for i in 1..=max_attempts {
@@ -95,11 +107,26 @@ fn sending_thread_fallible(url: String, token: String, max_attempts: u64) -> Res
 }
 
 pub fn send_synchronously(url: String, token: String, max_attempts: u64) {
-    if let Err(e) = sending_thread_fallible(url, token, max_attempts) {
+    send_synchronously_with(url, token, max_attempts, &PayloadBuilder::new());
+}
+
+/// Like [send_synchronously], but only with the collectors in `builder`.
+pub fn send_synchronously_with(
+    url: String,
+    token: String,
+    max_attempts: u64,
+    builder: &PayloadBuilder,
+) {
+    if let Err(e) = sending_thread_fallible(url, token, max_attempts, builder) {
         log::warn!("Unable to send metrics: {:?}", e);
     }
 }
 
 pub fn send_metrics(url: String, token: String) {
-    std::thread::spawn(|| send_synchronously(url, token, MAX_ATTEMPTS));
+    send_metrics_with(url, token, PayloadBuilder::new());
+}
+
+/// Like [send_metrics], but only with the collectors in `builder`.
+pub fn send_metrics_with(url: String, token: String, builder: PayloadBuilder) {
+    std::thread::spawn(move || send_synchronously_with(url, token, MAX_ATTEMPTS, &builder));
 }
@@ -0,0 +1,69 @@
+//! Cache sizes, from what Linux reports in sysfs.
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use hwsurvey_payloads::simdsp_bridger::CacheInfo;
+
+/// Where Linux describes the caches of the first CPU, relative to sysfs.
+const CACHE_DIR: &str = "devices/system/cpu/cpu0/cache";
+
+/// Parse a sysfs cache size, like `32K` or `8M`, into bytes.
+fn parse_cache_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let (digits, multiplier) = match size.as_bytes().last() {
+        Some(b'K') => (&size[..size.len() - 1], 1024),
+        Some(b'M') => (&size[..size.len() - 1], 1024 * 1024),
+        Some(b'G') => (&size[..size.len() - 1], 1024 * 1024 * 1024),
+        _ => (size, 1),
+    };
+    let n: u64 = digits
+        .parse()
+        .with_context(|| format!("Unable to parse cache size {}", size))?;
+    Ok(n * multiplier)
+}
+
+/// Read the caches of the first CPU from `sysfs`, which is usually `/sys`.
+pub fn read_caches(sysfs: &Path) -> Result<CacheInfo> {
+    let dir = sysfs.join(CACHE_DIR);
+    let mut caches = CacheInfo::default();
+
+    let entries =
+        std::fs::read_dir(&dir).with_context(|| format!("Unable to read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        let is_index = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .is_some_and(|x| x.starts_with("index"));
+        if !is_index {
+            continue;
+        }
+
+        let read = |name: &str| -> Result<String> {
+            let contents = std::fs::read_to_string(path.join(name))
+                .with_context(|| format!("Unable to read {}", path.join(name).display()))?;
+            Ok(contents.trim().to_string())
+        };
+        let level = read("level")?;
+        let kind = read("type")?;
+        let size = parse_cache_size(&read("size")?)?;
+
+        let slot = match (level.as_str(), kind.as_str()) {
+            ("1", "Instruction") => &mut caches.l1i,
+            ("1", "Data") => &mut caches.l1d,
+            ("1", "Unified") => &mut caches.l1u,
+            ("2", "Instruction") => &mut caches.l2i,
+            ("2", "Data") => &mut caches.l2d,
+            ("2", "Unified") => &mut caches.l2u,
+            ("3", "Instruction") => &mut caches.l3i,
+            ("3", "Data") => &mut caches.l3d,
+            ("3", "Unified") => &mut caches.l3u,
+            // L4 and the like aren't in the payload.
+            _ => continue,
+        };
+        *slot = size;
+    }
+
+    Ok(caches)
+}
@@ -0,0 +1,105 @@
+//! x86 features, from what Linux reports in `/proc/cpuinfo`.
+//!
+//! The CPU collector asks the CPU directly, which only ever describes the machine we're running on.  The kernel's flags
+//! say the same thing, and can be read from a recording of another machine.  Like `std::arch`, the kernel leaves out
+//! AVX and everything built on it if the OS doesn't save the AVX registers.
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use hwsurvey_payloads::simdsp_bridger::CpuCapabilities;
+
+/// Our name for the manufacturer with this cpuid vendor string.
+pub(crate) fn manufacturer(vendor_id: &str) -> &'static str {
+    match vendor_id {
+        "GenuineIntel" => "intel",
+        "AuthenticAMD" => "amd",
+        _ => "unknown",
+    }
+}
+
+/// The value of `key` for the first processor in `/proc/cpuinfo`.  Every processor lists the same flags.
+fn first_field<'a>(cpuinfo: &'a str, key: &str) -> Option<&'a str> {
+    cpuinfo.lines().find_map(|line| {
+        let (k, value) = line.split_once(':')?;
+        (k.trim() == key).then(|| value.trim())
+    })
+}
+
+/// Parse `/proc/cpuinfo` into the manufacturer and capabilities, or `None` if it lists no x86 flags.
+pub(crate) fn parse_cpuinfo(cpuinfo: &str) -> Option<(&'static str, CpuCapabilities)> {
+    let flags = first_field(cpuinfo, "flags")?
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    let has = |x: &str| flags.contains(&x);
+
+    let capabilities = CpuCapabilities {
+        x86_sse2: has("sse2"),
+        // The kernel calls SSE3 by Intel's early name for it, Prescott New Instructions.
+        x86_sse3: has("pni"),
+        x86_ssse3: has("ssse3"),
+        x86_sse4_1: has("sse4_1"),
+        x86_popcnt_insn: has("popcnt"),
+        x86_avx: has("avx"),
+        x86_avx2: has("avx2"),
+        x86_fma3: has("fma"),
+        x86_fma4: has("fma4"),
+        x86_xop: has("xop"),
+        x86_avx512f: has("avx512f"),
+        x86_avx512bw: has("avx512bw"),
+        x86_avx512dq: has("avx512dq"),
+        x86_avx512vl: has("avx512vl"),
+    };
+    let manufacturer = first_field(cpuinfo, "vendor_id").map_or("unknown", manufacturer);
+    Some((manufacturer, capabilities))
+}
+
+/// Read the manufacturer and x86 capabilities from `proc`, which is usually `/proc`.
+pub fn read_x86_cpu(proc: &Path) -> Result<(&'static str, CpuCapabilities)> {
+    let cpuinfo_path = proc.join("cpuinfo");
+    let cpuinfo = std::fs::read_to_string(&cpuinfo_path)
+        .with_context(|| format!("Unable to read {}", cpuinfo_path.display()))?;
+    parse_cpuinfo(&cpuinfo).with_context(|| format!("No x86 flags in {}", cpuinfo_path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (sse3, avx2, fma4, xop, avx512f, avx512bw, avx512dq, avx512vl)
+    fn flags(c: &CpuCapabilities) -> (bool, bool, bool, bool, bool, bool, bool, bool) {
+        (
+            c.x86_sse3,
+            c.x86_avx2,
+            c.x86_fma4,
+            c.x86_xop,
+            c.x86_avx512f,
+            c.x86_avx512bw,
+            c.x86_avx512dq,
+            c.x86_avx512vl,
+        )
+    }
+
+    #[test]
+    fn test_parse_cpuinfo() {
+        // The fixtures have neither AMD's FMA4 and XOP nor AVX-512, so check those here.
+        let bulldozer = "vendor_id\t: AuthenticAMD\nflags\t\t: sse2 pni ssse3 avx fma4 xop\n";
+        let (manufacturer, caps) = parse_cpuinfo(bulldozer).unwrap();
+        assert_eq!(manufacturer, "amd");
+        assert_eq!(
+            flags(&caps),
+            (true, false, true, true, false, false, false, false)
+        );
+
+        let skylake_x = "vendor_id\t: GenuineIntel\nflags\t\t: sse2 avx avx2 avx512f avx512dq avx512bw avx512vl\n";
+        let (manufacturer, caps) = parse_cpuinfo(skylake_x).unwrap();
+        assert_eq!(manufacturer, "intel");
+        assert_eq!(
+            flags(&caps),
+            (false, true, false, false, true, true, true, true)
+        );
+
+        assert_eq!(parse_cpuinfo("flags\t: sse2\n").unwrap().0, "unknown");
+        assert!(parse_cpuinfo("Features\t: fp asimd\n").is_none());
+    }
+}
@@ -0,0 +1,39 @@
+use serde::{Deserialize, Serialize};
+
+use crate::simdsp_bridger::{CacheInfo, CpuCapabilities};
+
+/// The CPU section of [crate::PayloadV2].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Cpu {
+    pub manufacturer: String,
+    pub architecture: String,
+
+    /// Either every cache or none: a partial section is rejected rather than guessing the rest are 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub caches: Option<CacheInfo>,
+
+    /// Either every flag or none, like the caches.  Clients which can't detect the flags leave the section out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<CpuCapabilities>,
+
+    /// Only sent by ARM machines.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arm_capabilities: Option<ArmCapabilities>,
+}
+
+/// SIMD features of ARM CPUs.  Flags missing from a payload are false.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ArmCapabilities {
+    /// Advanced SIMD, which every aarch64 CPU has but 32-bit ones may not.
+    pub neon: bool,
+    pub asimd_dotprod: bool,
+
+    /// Half-precision floats in Advanced SIMD.
+    pub fp16: bool,
+    pub sve: bool,
+    pub sve2: bool,
+
+    /// The SVE vector length in bits, or 0 without SVE.
+    pub sve_vector_length: u64,
+}
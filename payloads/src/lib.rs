@@ -1,5 +1,7 @@
+pub mod cpu;
 pub mod memory;
 pub mod simdsp_bridger;
+pub mod version;
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct PayloadV1 {
@@ -8,3 +10,123 @@ pub struct PayloadV1 {
     pub os: String,
     pub machine_id: String,
 }
+
+/// The payload sent to `/submit/v2`.
+///
+/// Everything but the machine id and OS is an optional section, so clients can leave out what they couldn't collect.
+/// Fields the server doesn't know about are ignored, so sections and fields can be added without breaking older servers.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct PayloadV2 {
+    pub version: version::Version<2>,
+    pub machine_id: String,
+    pub os: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<cpu::Cpu>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory: Option<memory::Memory>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_v2_versioning() {
+        let minimal =
+            r#"{"version": 2, "machine_id": "abc", "os": "linux", "from_the_future": {}}"#;
+        let parsed: PayloadV2 = serde_json::from_str(minimal).unwrap();
+        assert!(parsed.cpu.is_none());
+        assert!(parsed.memory.is_none());
+        assert_eq!(
+            serde_json::to_string(&parsed).unwrap(),
+            r#"{"version":2,"machine_id":"abc","os":"linux"}"#
+        );
+
+        let wrong = r#"{"version": 3, "machine_id": "abc", "os": "linux"}"#;
+        assert!(serde_json::from_str::<PayloadV2>(wrong).is_err());
+        let missing = r#"{"machine_id": "abc", "os": "linux"}"#;
+        assert!(serde_json::from_str::<PayloadV2>(missing).is_err());
+    }
+
+    fn v1_json(capabilities: &str) -> String {
+        format!(
+            r#"{{
+                "simdsp": {{
+                    "cpu_manufacturer": "intel",
+                    "cpu_architecture": "x86",
+                    "cache_info": {{"l1i": 32768, "l1d": 49152, "l1u": 0, "l2i": 0, "l2d": 0, "l2u": 1310720,
+                        "l3i": 0, "l3d": 0, "l3u": 25165824}},
+                    "cpu_capabilities": {{{}}}
+                }},
+                "memory": {{"total": 17179869184}},
+                "os": "windows",
+                "machine_id": "abc"
+            }}"#,
+            capabilities
+        )
+    }
+
+    #[test]
+    fn test_v1_requires_every_field() {
+        let all = r#""x86_sse2": true, "x86_sse3": true, "x86_ssse3": true, "x86_sse4_1": true,
+            "x86_popcnt_insn": true, "x86_avx": true, "x86_avx2": true, "x86_fma3": true, "x86_fma4": false,
+            "x86_xop": false, "x86_avx512f": false, "x86_avx512bw": false, "x86_avx512dq": false,
+            "x86_avx512vl": false"#;
+        let parsed: PayloadV1 = serde_json::from_str(&v1_json(all)).unwrap();
+        assert!(parsed.simdsp.cpu_capabilities.x86_avx2);
+        assert_eq!(parsed.simdsp.cache_info.l3u, 25165824);
+
+        let partial = r#""x86_sse2": true"#;
+        assert!(serde_json::from_str::<PayloadV1>(&v1_json(partial)).is_err());
+        let no_caches = v1_json(all).replace(r#""l1i": 32768, "#, "");
+        assert!(serde_json::from_str::<PayloadV1>(&no_caches).is_err());
+    }
+
+    #[test]
+    fn test_v2_rejects_incomplete_sections() {
+        let cpu_json = |caches: &str, capabilities: &str| {
+            format!(
+                r#"{{"version": 2, "machine_id": "abc", "os": "linux", "cpu": {{
+                    "manufacturer": "intel",
+                    "architecture": "x86",
+                    "caches": {},
+                    "capabilities": {}
+                }}}}"#,
+                caches, capabilities
+            )
+        };
+        let caches = r#"{"l1i": 32768, "l1d": 49152, "l1u": 0, "l2i": 0, "l2d": 0, "l2u": 1310720, "l3i": 0,
+            "l3d": 0, "l3u": 25165824}"#;
+        let capabilities = r#"{"x86_sse2": true, "x86_sse3": true, "x86_ssse3": true, "x86_sse4_1": true,
+            "x86_popcnt_insn": true, "x86_avx": true, "x86_avx2": true, "x86_fma3": true, "x86_fma4": false,
+            "x86_xop": false, "x86_avx512f": false, "x86_avx512bw": false, "x86_avx512dq": false,
+            "x86_avx512vl": false, "x86_avx1024": true}"#;
+
+        let cpu = serde_json::from_str::<PayloadV2>(&cpu_json(caches, capabilities))
+            .unwrap()
+            .cpu
+            .unwrap();
+        assert_eq!(cpu.caches.unwrap().l2u, 1310720);
+        assert!(cpu.capabilities.unwrap().x86_avx2);
+
+        let cpu = serde_json::from_str::<PayloadV2>(&cpu_json("null", "null"))
+            .unwrap()
+            .cpu
+            .unwrap();
+        assert!(cpu.caches.is_none());
+        assert!(cpu.capabilities.is_none());
+
+        let partial_caches = r#"{"l2u": 1310720}"#;
+        assert!(
+            serde_json::from_str::<PayloadV2>(&cpu_json(partial_caches, capabilities)).is_err()
+        );
+        let partial_capabilities = r#"{"x86_sse2": true}"#;
+        assert!(
+            serde_json::from_str::<PayloadV2>(&cpu_json(caches, partial_capabilities)).is_err()
+        );
+        let wrong_type = capabilities.replace(r#""x86_sse2": true"#, r#""x86_sse2": "yes""#);
+        assert!(serde_json::from_str::<PayloadV2>(&cpu_json(caches, &wrong_type)).is_err());
+    }
+}
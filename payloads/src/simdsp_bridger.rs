@@ -8,7 +8,8 @@ pub struct SystemInfo {
     pub cpu_capabilities: CpuCapabilities,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Every flag is required, in both V1 and V2 payloads.
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct CpuCapabilities {
     pub x86_sse2: bool,
     pub x86_sse3: bool,
@@ -26,7 +27,8 @@ pub struct CpuCapabilities {
     pub x86_avx512vl: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Cache sizes in bytes.  Caches a CPU doesn't have are 0.  Like [CpuCapabilities], every field is required.
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct CacheInfo {
     pub l1i: u64,
     pub l1d: u64,
@@ -0,0 +1,29 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A payload's version tag.
+///
+/// Serializes as `N`, and refuses to deserialize anything else, so that a payload can't be mistaken for another
+/// version of itself.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Version<const N: u32>;
+
+impl<const N: u32> Serialize for Version<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(N)
+    }
+}
+
+impl<'de, const N: u32> Deserialize<'de> for Version<N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let got = u32::deserialize(deserializer)?;
+        if got != N {
+            return Err(D::Error::custom(format!(
+                "Expected payload version {} but got {}",
+                N, got
+            )));
+        }
+        Ok(Version)
+    }
+}
@@ -0,0 +1,40 @@
+-- SIMD features of ARM machines, which cpu_capabilities can't describe since its flags are all x86.  Only ARM machines
+-- running clients new enough to send these have rows here.
+CREATE TABLE cpu_capabilities_arm(
+    -- Time, truncated to day in utc.
+    day TIMESTAMP WITH TIME ZONE NOT NULL,
+    application UUID NOT NULL,
+    os UUID NOT NULL,
+    cpu_manufacturer UUID NOT NULL,
+    architecture UUID NOT NULL,
+
+    neon BOOLEAN NOT NULL,
+    asimd_dotprod BOOLEAN NOT NULL,
+    fp16 BOOLEAN NOT NULL,
+    sve BOOLEAN NOT NULL,
+    sve2 BOOLEAN NOT NULL,
+
+    -- In bits, rounded down to a power of two, or 0 without SVE.
+    sve_vector_length BIGINT NOT NULL,
+
+    users_by_id hll NOT NULL,
+    users_by_ip hll NOT NULL,
+
+    FOREIGN KEY(os) REFERENCES os(id),
+    FOREIGN KEY(cpu_manufacturer) REFERENCES cpu_manufacturer(id),
+    FOREIGN KEY (application) REFERENCES application(id),
+    FOREIGN KEY (architecture) REFERENCES cpu_architecture(id),
+
+    CONSTRAINT cpu_capabilities_arm_upsert_constraint UNIQUE(day, application, cpu_manufacturer, os, architecture, neon,
+        asimd_dotprod, fp16, sve, sve2, sve_vector_length)
+);
+
+CREATE INDEX cpu_capabilities_arm_day ON cpu_capabilities_arm(day);
+
+-- See V6.
+CREATE TABLE cpu_capabilities_arm_weekly (LIKE cpu_capabilities_arm);
+CREATE TABLE cpu_capabilities_arm_monthly (LIKE cpu_capabilities_arm);
+ALTER TABLE cpu_capabilities_arm_weekly RENAME COLUMN day TO period;
+ALTER TABLE cpu_capabilities_arm_monthly RENAME COLUMN day TO period;
+CREATE INDEX cpu_capabilities_arm_weekly_period ON cpu_capabilities_arm_weekly(period);
+CREATE INDEX cpu_capabilities_arm_monthly_period ON cpu_capabilities_arm_monthly(period);
@@ -0,0 +1,17 @@
+-- Persist the rest of the x86 flags the client already sends.
+--
+-- These are nullable because rows written before this migration never recorded them, and claiming those machines
+-- lacked the feature would be wrong.  Every row written from here on has them set.
+ALTER TABLE cpu_capabilities
+    ADD COLUMN x86_popcnt_insn BOOLEAN,
+    ADD COLUMN x86_fma4 BOOLEAN,
+    ADD COLUMN x86_xop BOOLEAN,
+    ADD COLUMN x86_avx512bw BOOLEAN,
+    ADD COLUMN x86_avx512dq BOOLEAN,
+    ADD COLUMN x86_avx512vl BOOLEAN;
+
+-- The old constraint also left out x86_fma3, so rows differing only by FMA3 were merged into each other.
+ALTER TABLE cpu_capabilities DROP CONSTRAINT cpu_capabilities_upsert_constraint;
+ALTER TABLE cpu_capabilities ADD CONSTRAINT cpu_capabilities_upsert_constraint UNIQUE(day, application, cpu_manufacturer,
+    os, architecture, x86_sse2, x86_sse3, x86_ssse3, x86_sse4_1, x86_popcnt_insn, x86_fma3, x86_fma4, x86_xop, x86_avx,
+    x86_avx2, x86_avx512f, x86_avx512bw, x86_avx512dq, x86_avx512vl);
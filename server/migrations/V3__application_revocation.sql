@@ -0,0 +1,3 @@
+-- Applications are revoked rather than deleted, since the metrics tables reference them.  The server ignores tokens
+-- for revoked applications.
+ALTER TABLE application ADD COLUMN revoked_at TIMESTAMP WITH TIME ZONE;
@@ -0,0 +1,17 @@
+-- Tell running servers to reload their caches of dimension tables.  The payload is the name of the table which
+-- changed.
+CREATE FUNCTION notify_dimensions_changed() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('hwsurvey_dimensions', TG_TABLE_NAME);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER application_notify AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON application
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_dimensions_changed();
+CREATE TRIGGER cpu_manufacturer_notify AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON cpu_manufacturer
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_dimensions_changed();
+CREATE TRIGGER os_notify AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON os
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_dimensions_changed();
+CREATE TRIGGER cpu_architecture_notify AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON cpu_architecture
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_dimensions_changed();
@@ -0,0 +1,14 @@
+-- Values of dimension tables which clients sent but which we don't know about yet.  The server only records these
+-- when run with an allowlist, and an admin moves them into the real tables through the admin API.  Until then, data
+-- for them is written as unknown.
+CREATE TABLE pending_dimension(
+    -- Name of the table this value would go in.
+    dimension TEXT NOT NULL CHECK (dimension IN ('os', 'cpu_manufacturer', 'cpu_architecture')),
+    name TEXT NOT NULL,
+    -- Number of submissions, not machines.
+    times_seen BIGINT NOT NULL,
+    first_seen TIMESTAMP WITH TIME ZONE NOT NULL,
+    last_seen TIMESTAMP WITH TIME ZONE NOT NULL,
+
+    PRIMARY KEY(dimension, name)
+);
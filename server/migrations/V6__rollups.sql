@@ -0,0 +1,33 @@
+-- Weekly and monthly rollups of the metrics tables.  These are like the tables they roll up, except that `day` is
+-- replaced by `period`, the start of the week or month in UTC, and the hlls are unions over the period's days.  This
+-- is what lets reports count a machine seen on several days of a month once.
+--
+-- The server maintains these by recomputing recent periods in the background.  Nothing else should write to them.
+--
+-- Columns added to the metrics tables need to be added here too.
+CREATE TABLE cpu_capabilities_weekly (LIKE cpu_capabilities);
+CREATE TABLE cpu_capabilities_monthly (LIKE cpu_capabilities);
+CREATE TABLE cpu_caches_weekly (LIKE cpu_caches);
+CREATE TABLE cpu_caches_monthly (LIKE cpu_caches);
+CREATE TABLE memory_weekly (LIKE memory);
+CREATE TABLE memory_monthly (LIKE memory);
+CREATE TABLE cf_country_weekly (LIKE cf_country);
+CREATE TABLE cf_country_monthly (LIKE cf_country);
+
+ALTER TABLE cpu_capabilities_weekly RENAME COLUMN day TO period;
+ALTER TABLE cpu_capabilities_monthly RENAME COLUMN day TO period;
+ALTER TABLE cpu_caches_weekly RENAME COLUMN day TO period;
+ALTER TABLE cpu_caches_monthly RENAME COLUMN day TO period;
+ALTER TABLE memory_weekly RENAME COLUMN day TO period;
+ALTER TABLE memory_monthly RENAME COLUMN day TO period;
+ALTER TABLE cf_country_weekly RENAME COLUMN day TO period;
+ALTER TABLE cf_country_monthly RENAME COLUMN day TO period;
+
+CREATE INDEX cpu_capabilities_weekly_period ON cpu_capabilities_weekly(period);
+CREATE INDEX cpu_capabilities_monthly_period ON cpu_capabilities_monthly(period);
+CREATE INDEX cpu_caches_weekly_period ON cpu_caches_weekly(period);
+CREATE INDEX cpu_caches_monthly_period ON cpu_caches_monthly(period);
+CREATE INDEX memory_weekly_period ON memory_weekly(period);
+CREATE INDEX memory_monthly_period ON memory_monthly(period);
+CREATE INDEX cf_country_weekly_period ON cf_country_weekly(period);
+CREATE INDEX cf_country_monthly_period ON cf_country_monthly(period);
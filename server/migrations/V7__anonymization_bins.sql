@@ -0,0 +1,45 @@
+-- Versioned bins for the values we round down before storing them.  Rows of cpu_caches and memory record which version
+-- of their field's bins they were rounded with, so that reports can tell a change in banding from a change in machines.
+--
+-- Versions are only ever added.  The server adds one at startup when it's configured with bins which don't match any
+-- existing version of that field, and otherwise uses the matching or latest one.
+CREATE TABLE anonymization_bins(
+    field TEXT NOT NULL CHECK (field IN ('cache', 'memory')),
+    version INTEGER NOT NULL,
+
+    -- Lower edges of the bins, in bytes.  Anything below the first is stored as 0.
+    edges BIGINT[] NOT NULL,
+
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now(),
+
+    PRIMARY KEY(field, version)
+);
+
+-- What the server used before bins were configurable, which is what every existing row was rounded with.
+INSERT INTO anonymization_bins(field, version, edges) VALUES
+('cache', 1, ARRAY[0, 1024, 2048, 4096, 8192, 16384, 32768, 1048576, 4194304, 8388608, 16777216, 33554432, 67108864,
+    134217728, 268435456]),
+('memory', 1, ARRAY[1073741824, 2147483648, 4294967296, 8589934592, 17179869184]);
+
+-- Rows rounded with different versions are kept apart, since the same value can mean different things in each.  The
+-- defaults only exist to fill in existing rows.
+ALTER TABLE cpu_caches ADD COLUMN bins_version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE cpu_caches ALTER COLUMN bins_version DROP DEFAULT;
+ALTER TABLE cpu_caches DROP CONSTRAINT cpu_caches_upsert_constraint;
+ALTER TABLE cpu_caches ADD CONSTRAINT cpu_caches_upsert_constraint UNIQUE(application, day, bins_version, l1i, l1d, l1u,
+    l2i, l2d, l2u, l3i, l3d, l3u);
+
+ALTER TABLE memory ADD COLUMN bins_version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE memory ALTER COLUMN bins_version DROP DEFAULT;
+ALTER TABLE memory DROP CONSTRAINT memory_upsert_constraint;
+ALTER TABLE memory ADD CONSTRAINT memory_upsert_constraint UNIQUE(application, day, bins_version, total_memory);
+
+-- See V6.
+ALTER TABLE cpu_caches_weekly ADD COLUMN bins_version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE cpu_caches_weekly ALTER COLUMN bins_version DROP DEFAULT;
+ALTER TABLE cpu_caches_monthly ADD COLUMN bins_version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE cpu_caches_monthly ALTER COLUMN bins_version DROP DEFAULT;
+ALTER TABLE memory_weekly ADD COLUMN bins_version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE memory_weekly ALTER COLUMN bins_version DROP DEFAULT;
+ALTER TABLE memory_monthly ADD COLUMN bins_version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE memory_monthly ALTER COLUMN bins_version DROP DEFAULT;
@@ -0,0 +1,25 @@
+-- A new version of the memory bins: powers of two from 512 MB to 256 GB.  Version 1 stopped at 16 GB, so 16, 32, and 64
+-- GB machines all looked the same, and put everything under 1 GB at 0.  This is normally version 2, unless a server had
+-- already added versions from its config.  Anything under 512 MB is still stored as 0, so the rare tiny machines share
+-- one floor bucket rather than getting bins of their own.
+--
+-- Servers which don't configure memory bins start using this version when they restart.
+--
+-- Existing rows are not backfilled.  Only the band of a version 1 row was stored, and a row at 16 GB could be any
+-- machine with 16 GB or more, so there's nothing to move it to.  Instead:
+--
+-- * Version 1 rows keep version 1, and reports which group by bins_version keep them apart.
+-- * Every edge of version 1 is also an edge of the new version, so "at least N" is still true of a new row when read as
+--   a version 1 band.  To compare across the change, map new rows onto version 1 with
+--   `CASE WHEN total_memory >= 17179869184 THEN 17179869184 WHEN total_memory < 1073741824 THEN 0 ELSE total_memory END`.
+-- * The HTML reports label memory as "at least", which is correct for both versions, so they need no change.
+-- * Once no report range reaches back before the change, version 1 rows can simply be left to age out.
+INSERT INTO anonymization_bins(field, version, edges)
+SELECT 'memory', COALESCE(MAX(version), 0) + 1, ARRAY[536870912, 1073741824, 2147483648, 4294967296, 8589934592,
+    17179869184, 34359738368, 68719476736, 137438953472, 274877906944]
+FROM anonymization_bins WHERE field = 'memory'
+-- A server may already have added these edges from its config.
+HAVING NOT EXISTS (
+    SELECT 1 FROM anonymization_bins WHERE field = 'memory' AND edges = ARRAY[536870912, 1073741824, 2147483648,
+        4294967296, 8589934592, 17179869184, 34359738368, 68719476736, 137438953472, 274877906944]::BIGINT[]
+);
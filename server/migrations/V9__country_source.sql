@@ -0,0 +1,16 @@
+-- Where each country came from: the proxy's country header (Cloudflare's by default), a lookup of the IP in the
+-- server's GeoIP database, or neither, in which case the country is the configured unknown one.
+--
+-- Existing rows all came from the header, or were unknown because it was missing.  Cloudflare also uses XX for
+-- countries it doesn't know, so the two can't be told apart and are left as cloudflare.
+ALTER TABLE cf_country ADD COLUMN source TEXT NOT NULL DEFAULT 'cloudflare'
+    CHECK (source IN ('cloudflare', 'geoip', 'unknown'));
+ALTER TABLE cf_country ALTER COLUMN source DROP DEFAULT;
+ALTER TABLE cf_country DROP CONSTRAINT cf_country_upsert_constraint;
+ALTER TABLE cf_country ADD CONSTRAINT cf_country_upsert_constraint UNIQUE(application, day, source, country);
+
+-- See V6.
+ALTER TABLE cf_country_weekly ADD COLUMN source TEXT NOT NULL DEFAULT 'cloudflare';
+ALTER TABLE cf_country_weekly ALTER COLUMN source DROP DEFAULT;
+ALTER TABLE cf_country_monthly ADD COLUMN source TEXT NOT NULL DEFAULT 'cloudflare';
+ALTER TABLE cf_country_monthly ALTER COLUMN source DROP DEFAULT;
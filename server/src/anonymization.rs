@@ -1,99 +1,415 @@
-const KB: u64 = 1024;
-const MB: u64 = KB * 1024;
-const GB: u64 = 1024 * MB;
-
-const CACHE_BINS: &[u64] = &[
-    0,
-    KB,
-    2 * KB,
-    4 * KB,
-    8 * KB,
-    16 * KB,
-    32 * KB,
-    MB,
-    4 * MB,
-    8 * MB,
-    16 * MB,
-    32 * MB,
-    64 * MB,
-    128 * MB,
-    256 * MB,
-    u64::MAX,
-];
-
-const MEM_BINS: &[u64] = &[GB, 2 * GB, 4 * GB, 8 * GB, 16 * GB, u64::MAX];
-
-#[derive(Debug)]
-pub struct CpuCapabilitiesRow {
-    pub architecture: String,
-    pub manufacturer: String,
-    pub x86_sse2: bool,
-    pub x86_sse3: bool,
-    pub x86_ssse3: bool,
-    pub x86_sse4_1: bool,
-    pub x86_fma3: bool,
-    pub x86_avx: bool,
-    pub x86_avx2: bool,
-    pub x86_avx512f: bool,
+//! Rounding of cache sizes and memory into bins before they're stored.
+//!
+//! The bins for each field are versioned in the `anonymization_bins` table, and rows record the version they were
+//! rounded with.  At startup the server either uses the latest version, or, if bins are configured, the version with
+//! those edges, adding one if there isn't one yet.  Version 1 of each is what used to be hard-coded here; see the V7
+//! migration.
+//!
+//! SVE vector lengths are also rounded, but with fixed bins, since the possible values are fixed by the architecture.
+//!
+//! A bin which only a few machines land in narrows those machines down almost as much as not binning at all, so at
+//! startup we also warn about memory bins which recently held fewer than `min_bin_population` machines.
+use anyhow::{Context, Result};
+use tokio_postgres::{Client, GenericClient, Transaction};
+
+fn bin(input: u64, bins: &[u64]) -> u64 {
+    let mut out = 0;
+
+    for i in bins.iter() {
+        if *i > input {
+            break;
+        }
+
+        out = *i;
+    }
+
+    out
 }
 
-#[derive(Debug)]
-pub struct CpuCachesRow {
-    pub architecture: String,
-    pub manufacturer: String,
-
-    pub l1i: u64,
-    pub l1d: u64,
-    pub l1u: u64,
-    pub l2i: u64,
-    pub l2d: u64,
-    pub l2u: u64,
-    pub l3i: u64,
-    pub l3d: u64,
-    pub l3u: u64,
+/// SVE vector lengths are multiples of 128 bits, up to 2048.  Only powers of two are common, so round anything else down
+/// to one rather than let an unusual length pick out a machine.
+const SVE_VECTOR_LENGTH_BINS: &[u64] = &[128, 256, 512, 1024, 2048];
+
+pub fn round_sve_vector_length(bits: u64) -> u64 {
+    bin(bits, SVE_VECTOR_LENGTH_BINS)
 }
 
-#[derive(Debug)]
-pub struct OsRow {
-    pub os: String,
-    pub architecture: String,
+/// How far back to look when counting the machines in each bin.
+const POPULATION_DAYS: i64 = 30;
+
+/// Bins to use instead of the latest version in the database.
+///
+/// Each list is the lower edges of its bins.  Anything below the first edge becomes 0.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BinsConfig {
+    pub cache: Option<Vec<u64>>,
+    pub memory: Option<Vec<u64>>,
+
+    /// Warn about bins with fewer machines than this.  The default matches the default `--min-users` of
+    /// `render-reports`, below which the reports fold values into "other" anyway.  0 turns the check off.
+    pub min_bin_population: u64,
 }
 
-#[derive(Debug)]
-pub struct MemoryRow {
-    pub os: &'static str,
-    pub manufacturer: &'static str,
-    pub total_mem: u64,
+impl Default for BinsConfig {
+    fn default() -> BinsConfig {
+        BinsConfig {
+            cache: None,
+            memory: None,
+            min_bin_population: 10,
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct Rows {
-    pub cpu_capabilities: CpuCapabilitiesRow,
-    pub cpu_caches: CpuCachesRow,
-    pub os: OsRow,
-    pub memory: MemoryRow,
+impl BinsConfig {
+    /// Check that every configured list is non-empty, strictly increasing, and fits in a Postgres BIGINT.
+    pub fn validate(&self) -> Result<()> {
+        for (name, bins) in [("cache", &self.cache), ("memory", &self.memory)] {
+            let bins = match bins {
+                Some(b) => b,
+                None => continue,
+            };
+            if bins.is_empty() {
+                anyhow::bail!("{} bins must not be empty", name);
+            }
+            if bins.windows(2).any(|w| w[0] >= w[1]) {
+                anyhow::bail!("{} bins must be strictly increasing", name);
+            }
+            if bins.iter().any(|x| *x > i64::MAX as u64) {
+                anyhow::bail!("{} bins must fit in a signed 64-bit integer", name);
+            }
+        }
+        Ok(())
+    }
 }
 
-fn bin(input: u64, bins: &[u64]) -> u64 {
-    let mut out = 0;
+/// One version of the bins for one field.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BinSet {
+    pub version: i32,
+    pub edges: Vec<u64>,
+}
 
-    for i in bins.iter() {
-        if *i > input {
-            break;
+/// The bins values are rounded down into before they're stored.
+#[derive(Clone, Debug)]
+pub struct Bins {
+    pub cache: BinSet,
+    pub memory: BinSet,
+}
+
+impl Bins {
+    /// Round a cache size to one of our bins for anonymization purposes.
+    pub fn round_cache(&self, cache: u64) -> u64 {
+        bin(cache, &self.cache.edges)
+    }
+
+    /// Round the memory to one of our bins for anonymization purposes.
+    pub fn round_mem(&self, mem: u64) -> u64 {
+        bin(mem, &self.memory.edges)
+    }
+}
+
+/// Find the version of `field`'s bins to use, adding `configured` as a new version if it's set and doesn't match one.
+async fn resolve_field(
+    tx: &Transaction<'_>,
+    field: &str,
+    configured: Option<&[u64]>,
+) -> Result<BinSet> {
+    let versions = tx
+        .query(
+            "SELECT version, edges FROM anonymization_bins WHERE field = $1 ORDER BY version",
+            &[&field],
+        )
+        .await?
+        .into_iter()
+        .map(|row| BinSet {
+            version: row.get(0),
+            edges: row
+                .get::<_, Vec<i64>>(1)
+                .into_iter()
+                .map(|x| x as u64)
+                .collect(),
+        })
+        .collect::<Vec<_>>();
+
+    let edges = match configured {
+        None => {
+            return versions
+                .last()
+                .cloned()
+                .with_context(|| format!("No {} bins in the database", field))
         }
+        Some(e) => e,
+    };
 
-        out = *i;
+    if let Some(existing) = versions.iter().find(|v| v.edges == edges) {
+        return Ok(existing.clone());
     }
 
-    out
+    let version = versions.last().map(|v| v.version).unwrap_or(0) + 1;
+    let db_edges = edges.iter().map(|x| *x as i64).collect::<Vec<_>>();
+    tx.execute(
+        "INSERT INTO anonymization_bins(field, version, edges) VALUES($1, $2, $3)",
+        &[&field, &version, &db_edges],
+    )
+    .await?;
+    log::info!("Added version {} of the {} bins", version, field);
+
+    Ok(BinSet {
+        version,
+        edges: edges.to_vec(),
+    })
 }
 
-/// Round the cache to one of our hard-coded bins for anonymization purposes.
-pub fn round_cache(cache: u64) -> u64 {
-    bin(cache, CACHE_BINS)
+/// Work out which bins to use, recording configured bins as new versions if needed.
+pub async fn load(client: &mut Client, config: &BinsConfig) -> Result<Bins> {
+    let tx = client.transaction().await?;
+    // Two servers starting at once with the same new bins must not both add a version.
+    tx.execute("LOCK TABLE anonymization_bins IN EXCLUSIVE MODE", &[])
+        .await?;
+    let cache = resolve_field(&tx, "cache", config.cache.as_deref()).await?;
+    let memory = resolve_field(&tx, "memory", config.memory.as_deref()).await?;
+    tx.commit().await?;
+
+    Ok(Bins { cache, memory })
+}
+
+/// Edges of bins which hold some machines, but fewer than `threshold`.  `counts` is pairs of rounded values and how
+/// many machines had them.
+fn sparse_bins(counts: impl IntoIterator<Item = (u64, u64)>, threshold: u64) -> Vec<u64> {
+    let mut totals = std::collections::BTreeMap::<u64, u64>::new();
+    for (edge, machines) in counts {
+        *totals.entry(edge).or_default() += machines;
+    }
+    totals
+        .into_iter()
+        .filter(|(_, machines)| *machines > 0 && *machines < threshold)
+        .map(|(edge, _)| edge)
+        .collect()
+}
+
+/// Memory bins which held some machines, but fewer than `threshold`, over the last [POPULATION_DAYS] days.
+async fn sparse_memory_bins(
+    client: &impl GenericClient,
+    bins: &BinSet,
+    threshold: u64,
+) -> Result<Vec<u64>> {
+    let counts = client
+        .query(
+            r#"
+SELECT total_memory, hll_cardinality(hll_union_agg(users_by_id))
+FROM memory
+WHERE bins_version = $1 AND day >= now() - $2 * interval '1 day'
+GROUP BY total_memory"#,
+            &[&bins.version, &(POPULATION_DAYS as f64)],
+        )
+        .await?
+        .into_iter()
+        .map(|row| {
+            let machines: Option<f64> = row.get(1);
+            (row.get::<_, i64>(0) as u64, machines.unwrap_or(0.0) as u64)
+        });
+
+    Ok(sparse_bins(counts, threshold))
+}
+
+/// Warn about memory bins which held fewer than `threshold` machines over the last [POPULATION_DAYS] days.
+pub async fn check_memory_population(client: &Client, bins: &BinSet, threshold: u64) -> Result<()> {
+    if threshold == 0 {
+        return Ok(());
+    }
+
+    let sparse = sparse_memory_bins(client, bins, threshold).await?;
+    if !sparse.is_empty() {
+        log::warn!(
+            "Memory bins starting at {:?} held fewer than {} machines over the last {} days, which narrows those \
+             machines down.  Consider coarser bins",
+            sparse,
+            threshold,
+            POPULATION_DAYS
+        );
+    }
+    Ok(())
 }
 
-/// Round the memory to one of our hard-coded bins for anonymization purposes.
-pub fn round_mem(mem: u64) -> u64 {
-    bin(mem, MEM_BINS)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GB: u64 = 1 << 30;
+
+    /// Version 1 of the memory bins, added by the V7 migration.
+    const MEMORY_BINS_V1: &[u64] = &[GB, 2 * GB, 4 * GB, 8 * GB, 16 * GB];
+
+    /// The memory bins added by the V8 migration: powers of two from 512 MB to 256 GB.
+    const MEMORY_BINS: &[u64] = &[
+        GB / 2,
+        GB,
+        2 * GB,
+        4 * GB,
+        8 * GB,
+        16 * GB,
+        32 * GB,
+        64 * GB,
+        128 * GB,
+        256 * GB,
+    ];
+
+    /// Roughly the shape of memory sizes in desktop hardware surveys, as (GB, machines out of 10000).
+    const POPULATION: &[(u64, u64)] = &[
+        (2, 40),
+        (3, 5),
+        (4, 250),
+        (6, 60),
+        (8, 2000),
+        (12, 500),
+        (16, 4800),
+        (24, 150),
+        (32, 1900),
+        (48, 20),
+        (64, 220),
+        (128, 40),
+        (192, 3),
+        (256, 20),
+    ];
+
+    #[test]
+    fn test_memory_bins_are_valid() {
+        BinsConfig {
+            memory: Some(MEMORY_BINS.to_vec()),
+            ..Default::default()
+        }
+        .validate()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_memory_bins_distinguish_modern_machines() {
+        let rounded = [16, 32, 64, 128, 256]
+            .iter()
+            .map(|x| bin(x * GB, MEMORY_BINS))
+            .collect::<Vec<_>>();
+        assert_eq!(rounded, vec![16 * GB, 32 * GB, 64 * GB, 128 * GB, 256 * GB]);
+
+        assert_eq!(bin(1024 * GB, MEMORY_BINS), 256 * GB);
+        assert_eq!(bin(GB / 2, MEMORY_BINS), GB / 2);
+        // The floor bucket.
+        assert_eq!(bin(GB / 4, MEMORY_BINS), 0);
+        assert_eq!(bin(0, MEMORY_BINS), 0);
+    }
+
+    /// The V8 migration relies on this to say that version 1 rows and new rows can be compared as "at least N".
+    #[test]
+    fn test_memory_bins_keep_version_1_edges() {
+        for edge in MEMORY_BINS_V1 {
+            assert!(MEMORY_BINS.contains(edge), "{} was dropped", edge);
+        }
+    }
+
+    #[test]
+    fn test_sparse_bins() {
+        assert_eq!(
+            sparse_bins([(0, 3), (GB, 4), (GB, 6), (2 * GB, 9), (4 * GB, 0)], 10),
+            vec![0, 2 * GB]
+        );
+        assert_eq!(sparse_bins([(0, 3)], 0), Vec::<u64>::new());
+    }
+
+    /// Needs a database the server has migrated, in `HWSURVEY_TEST_DATABASE_URL`.
+    #[tokio::test]
+    #[ignore = "needs a database; set HWSURVEY_TEST_DATABASE_URL"]
+    async fn test_migrations_add_these_memory_bins() {
+        let url = std::env::var("HWSURVEY_TEST_DATABASE_URL").unwrap();
+        let client = crate::db::connect(&url, "tests").await.unwrap();
+        let versions = client
+            .query(
+                "SELECT edges FROM anonymization_bins WHERE field = 'memory' ORDER BY version",
+                &[],
+            )
+            .await
+            .unwrap()
+            .iter()
+            .map(|r| {
+                r.get::<_, Vec<i64>>(0)
+                    .into_iter()
+                    .map(|x| x as u64)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(versions[0], MEMORY_BINS_V1);
+        assert!(versions.iter().any(|v| v == MEMORY_BINS));
+    }
+
+    /// Needs a database the server has migrated, in `HWSURVEY_TEST_DATABASE_URL`.  Fills `memory` with [POPULATION],
+    /// rounded with the bins the V8 migration added, inside a transaction which is rolled back.
+    #[tokio::test]
+    #[ignore = "needs a database; set HWSURVEY_TEST_DATABASE_URL"]
+    async fn test_memory_bins_population() {
+        let url = std::env::var("HWSURVEY_TEST_DATABASE_URL").unwrap();
+        let mut client = crate::db::connect(&url, "tests").await.unwrap();
+        let config = BinsConfig {
+            memory: Some(MEMORY_BINS.to_vec()),
+            ..Default::default()
+        };
+        let bins = load(&mut client, &config).await.unwrap();
+        let threshold = config.min_bin_population;
+
+        let tx = client.transaction().await.unwrap();
+        // Only count our machines.
+        tx.execute(
+            "DELETE FROM memory WHERE bins_version = $1",
+            &[&bins.memory.version],
+        )
+        .await
+        .unwrap();
+        let application = uuid::Uuid::new_v4();
+        tx.execute(
+            "INSERT INTO application(id, name) VALUES($1, $2)",
+            &[&application, &format!("test {}", application)],
+        )
+        .await
+        .unwrap();
+
+        let add_machines = |bytes: u64, machines: u64| {
+            let tx = &tx;
+            let total = bins.round_mem(bytes) as i64;
+            async move {
+                tx.execute(
+                    r#"
+INSERT INTO memory(application, day, bins_version, total_memory, users_by_id)
+SELECT $1, now(), $2, $3, hll_add_agg(hll_hash_text($4::text || g::text)) FROM generate_series(1, $5::int) g
+ON CONFLICT ON CONSTRAINT memory_upsert_constraint
+DO UPDATE SET users_by_id = memory.users_by_id || EXCLUDED.users_by_id"#,
+                    &[
+                        &application,
+                        &bins.memory.version,
+                        &total,
+                        &format!("{}-", bytes),
+                        &(machines as i32),
+                    ],
+                )
+                .await
+                .unwrap();
+            }
+        };
+
+        for (gb, machines) in POPULATION {
+            add_machines(gb * GB, *machines).await;
+        }
+        assert_eq!(
+            sparse_memory_bins(&tx, &bins.memory, threshold)
+                .await
+                .unwrap(),
+            Vec::<u64>::new()
+        );
+
+        // Make sure the check can fail: a few tiny machines get the floor bucket to themselves.
+        add_machines(GB / 4, 3).await;
+        assert_eq!(
+            sparse_memory_bins(&tx, &bins.memory, threshold)
+                .await
+                .unwrap(),
+            vec![0]
+        );
+    }
 }
@@ -0,0 +1,436 @@
+//! Admin API for managing the applications which are allowed to submit, and for approving OSes, CPU manufacturers,
+//! and CPU architectures found by dimension discovery.
+//!
+//! All routes require an `Authorization: Bearer <token>` header matching `admin.token`.  If no token was configured,
+//! every request is rejected.
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+use warp::Filter;
+
+use crate::uuid_cache::Dimension;
+use crate::writer::WriterThread;
+
+/// Postgres error code for unique constraint violations.
+const UNIQUE_VIOLATION: &str = "23505";
+
+/// Everything the admin handlers need.
+pub struct AdminContext {
+    /// Tells the writer about new and revoked applications.
+    pub writer: Arc<WriterThread>,
+
+    /// The admin API's own connections, so that admin requests never hold up the writers and the other way around.
+    pub pool: Pool,
+    pub token: Option<String>,
+}
+
+/// A request without the admin token.  Rejecting with this before reading the body means unauthenticated callers
+/// learn nothing from how we parse it.
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+#[derive(Serialize)]
+pub struct Application {
+    id: Uuid,
+    name: String,
+    revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct ApplicationName {
+    name: String,
+}
+
+#[derive(Serialize)]
+pub struct PendingDimension {
+    dimension: String,
+    name: String,
+    times_seen: i64,
+    first_seen: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+}
+
+/// Identifies a pending dimension value.  `dimension` is the name of the table, e.g. `cpu_manufacturer`.
+#[derive(Deserialize)]
+pub struct DimensionValue {
+    dimension: String,
+    name: String,
+}
+
+/// Compare in constant time, so that response timing doesn't tell an attacker how much of the token they got right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+/// Whether `header`, the request's `Authorization` header, carries the admin token.  Also used by [super::reports].
+pub(crate) fn is_authorized(expected: Option<&str>, header: Option<&str>) -> bool {
+    let (expected, header) = match (expected, header) {
+        (Some(e), Some(h)) => (e, h),
+        _ => return false,
+    };
+
+    match header.strip_prefix("Bearer ") {
+        Some(given) => constant_time_eq(given.as_bytes(), expected.as_bytes()),
+        None => false,
+    }
+}
+
+fn status(code: StatusCode) -> Response {
+    warp::reply::with_status(warp::reply::reply(), code).into_response()
+}
+
+fn is_unique_violation(e: &tokio_postgres::Error) -> bool {
+    e.code().map(|c| c.code()) == Some(UNIQUE_VIOLATION)
+}
+
+fn validate_name(name: &str) -> bool {
+    !name.trim().is_empty() && name.len() <= 256
+}
+
+async fn list_applications(ctx: &AdminContext) -> Result<Response> {
+    let client = ctx.pool.get().await?;
+    let rows = client
+        .query(
+            "SELECT id, name, revoked_at FROM application ORDER BY name",
+            &[],
+        )
+        .await?;
+
+    let apps: Vec<Application> = rows
+        .into_iter()
+        .map(|r| Application {
+            id: r.get("id"),
+            name: r.get("name"),
+            revoked_at: r.get("revoked_at"),
+        })
+        .collect();
+
+    Ok(warp::reply::json(&apps).into_response())
+}
+
+async fn create_application(ctx: &AdminContext, body: ApplicationName) -> Result<Response> {
+    if !validate_name(&body.name) {
+        return Ok(status(StatusCode::BAD_REQUEST));
+    }
+    let client = ctx.pool.get().await?;
+
+    let id = Uuid::new_v4();
+    match client
+        .execute(
+            "INSERT INTO application(id, name) VALUES($1, $2)",
+            &[&id, &body.name],
+        )
+        .await
+    {
+        Ok(_) => {}
+        Err(e) if is_unique_violation(&e) => return Ok(status(StatusCode::CONFLICT)),
+        Err(e) => return Err(e.into()),
+    }
+
+    ctx.writer.add_application(id);
+    log::info!("Registered application {} with token {}", body.name, id);
+
+    let app = Application {
+        id,
+        name: body.name,
+        revoked_at: None,
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&app), StatusCode::CREATED).into_response())
+}
+
+async fn rename_application(
+    ctx: &AdminContext,
+    id: Uuid,
+    body: ApplicationName,
+) -> Result<Response> {
+    if !validate_name(&body.name) {
+        return Ok(status(StatusCode::BAD_REQUEST));
+    }
+    let client = ctx.pool.get().await?;
+
+    let updated = match client
+        .execute(
+            "UPDATE application SET name = $2 WHERE id = $1",
+            &[&id, &body.name],
+        )
+        .await
+    {
+        Ok(n) => n,
+        Err(e) if is_unique_violation(&e) => return Ok(status(StatusCode::CONFLICT)),
+        Err(e) => return Err(e.into()),
+    };
+
+    if updated == 0 {
+        return Ok(status(StatusCode::NOT_FOUND));
+    }
+
+    Ok(status(StatusCode::NO_CONTENT))
+}
+
+async fn revoke_application(ctx: &AdminContext, id: Uuid) -> Result<Response> {
+    let client = ctx.pool.get().await?;
+    let updated = client
+        .execute(
+            "UPDATE application SET revoked_at = now() WHERE id = $1 AND revoked_at IS NULL",
+            &[&id],
+        )
+        .await?;
+
+    // Either it doesn't exist or it was already revoked.  Either way, make sure we aren't accepting it.
+    ctx.writer.remove_application(&id);
+
+    if updated == 0 {
+        return Ok(status(StatusCode::NOT_FOUND));
+    }
+
+    log::info!("Revoked application {}", id);
+    Ok(status(StatusCode::NO_CONTENT))
+}
+
+async fn list_pending_dimensions(ctx: &AdminContext) -> Result<Response> {
+    let client = ctx.pool.get().await?;
+    let rows = client
+        .query(
+            "SELECT dimension, name, times_seen, first_seen, last_seen FROM pending_dimension
+            ORDER BY dimension, times_seen DESC",
+            &[],
+        )
+        .await?;
+
+    let pending: Vec<PendingDimension> = rows
+        .into_iter()
+        .map(|r| PendingDimension {
+            dimension: r.get("dimension"),
+            name: r.get("name"),
+            times_seen: r.get("times_seen"),
+            first_seen: r.get("first_seen"),
+            last_seen: r.get("last_seen"),
+        })
+        .collect();
+
+    Ok(warp::reply::json(&pending).into_response())
+}
+
+/// Move a pending value into its dimension table.
+///
+/// The uuid cache picks this up through the notification on the dimension table.  Data which was already written as
+/// unknown stays that way.
+async fn approve_dimension(ctx: &AdminContext, body: DimensionValue) -> Result<Response> {
+    let dimension = match Dimension::from_table_name(&body.dimension) {
+        Some(d) => d,
+        None => return Ok(status(StatusCode::BAD_REQUEST)),
+    };
+    let client = ctx.pool.get().await?;
+
+    let id = Uuid::new_v4();
+    let query = format!(
+        r#"
+WITH approved AS (
+    DELETE FROM pending_dimension WHERE dimension = $1 AND name = $2 RETURNING name
+)
+INSERT INTO {}(id, name) SELECT $3, name FROM approved"#,
+        dimension.table_name()
+    );
+
+    let inserted = match client
+        .execute(&query, &[&body.dimension, &body.name, &id])
+        .await
+    {
+        Ok(n) => n,
+        Err(e) if is_unique_violation(&e) => return Ok(status(StatusCode::CONFLICT)),
+        Err(e) => return Err(e.into()),
+    };
+
+    if inserted == 0 {
+        return Ok(status(StatusCode::NOT_FOUND));
+    }
+
+    log::info!(
+        "Approved {} {} with id {}",
+        dimension.table_name(),
+        body.name,
+        id
+    );
+    Ok(status(StatusCode::NO_CONTENT))
+}
+
+/// Forget a pending value.  If clients keep sending it, it will show up again.
+async fn reject_dimension(ctx: &AdminContext, body: DimensionValue) -> Result<Response> {
+    let client = ctx.pool.get().await?;
+    let deleted = client
+        .execute(
+            "DELETE FROM pending_dimension WHERE dimension = $1 AND name = $2",
+            &[&body.dimension, &body.name],
+        )
+        .await?;
+
+    if deleted == 0 {
+        return Ok(status(StatusCode::NOT_FOUND));
+    }
+
+    Ok(status(StatusCode::NO_CONTENT))
+}
+
+fn json_body<T: serde::de::DeserializeOwned + Send>(
+) -> impl Filter<Extract = (T,), Error = warp::Rejection> + Copy {
+    warp::filters::body::content_length_limit(1024 * 10).and(warp::body::json())
+}
+
+/// Turn the result of a handler into a reply, logging errors.
+///
+/// Unlike the submission API, admins are trusted enough to know that something went wrong on our end.
+fn finish(res: Result<Response>) -> Response {
+    match res {
+        Ok(r) => r,
+        Err(e) => {
+            only_every::only_every!(Duration::from_secs(3), {
+                log::error!("Could not handle admin request because {:?}", e);
+            });
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+pub fn routes(
+    ctx: Arc<AdminContext>,
+) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    let with_ctx = {
+        let ctx = ctx.clone();
+        warp::any().map(move || ctx.clone())
+    };
+
+    // Resolves to the context, or rejects with [Unauthorized] if the request didn't carry the admin token.
+    let auth = with_ctx
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            |ctx: Arc<AdminContext>, header: Option<String>| async move {
+                if is_authorized(ctx.token.as_deref(), header.as_deref()) {
+                    Ok(ctx)
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            },
+        );
+
+    let list = warp::path!("admin" / "applications")
+        .and(warp::get())
+        .and(auth.clone())
+        .then(|ctx: Arc<AdminContext>| async move { finish(list_applications(&ctx).await) });
+
+    let create = warp::path!("admin" / "applications")
+        .and(warp::post())
+        .and(auth.clone())
+        .and(json_body())
+        .then(|ctx: Arc<AdminContext>, body: ApplicationName| async move {
+            finish(create_application(&ctx, body).await)
+        });
+
+    let rename = warp::path!("admin" / "applications" / Uuid)
+        .and(warp::patch())
+        .and(auth.clone())
+        .and(json_body())
+        .then(
+            |id: Uuid, ctx: Arc<AdminContext>, body: ApplicationName| async move {
+                finish(rename_application(&ctx, id, body).await)
+            },
+        );
+
+    let revoke = warp::path!("admin" / "applications" / Uuid)
+        .and(warp::delete())
+        .and(auth.clone())
+        .then(|id: Uuid, ctx: Arc<AdminContext>| async move {
+            finish(revoke_application(&ctx, id).await)
+        });
+
+    let list_pending = warp::path!("admin" / "dimensions" / "pending")
+        .and(warp::get())
+        .and(auth.clone())
+        .then(|ctx: Arc<AdminContext>| async move { finish(list_pending_dimensions(&ctx).await) });
+
+    let approve = warp::path!("admin" / "dimensions" / "approve")
+        .and(warp::post())
+        .and(auth.clone())
+        .and(json_body())
+        .then(|ctx: Arc<AdminContext>, body: DimensionValue| async move {
+            finish(approve_dimension(&ctx, body).await)
+        });
+
+    let reject = warp::path!("admin" / "dimensions" / "reject")
+        .and(warp::post())
+        .and(auth)
+        .and(json_body())
+        .then(|ctx: Arc<AdminContext>, body: DimensionValue| async move {
+            finish(reject_dimension(&ctx, body).await)
+        });
+
+    list.or(create)
+        .unify()
+        .or(rename)
+        .unify()
+        .or(revoke)
+        .unify()
+        .or(list_pending)
+        .unify()
+        .or(approve)
+        .unify()
+        .or(reject)
+        .unify()
+        .recover(|rejection: warp::Rejection| async move {
+            match rejection.find::<Unauthorized>() {
+                Some(_) => Ok(status(StatusCode::UNAUTHORIZED)),
+                None => Err(rejection),
+            }
+        })
+        .unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"secret", b""));
+    }
+
+    #[test]
+    fn test_is_authorized() {
+        let token = Some("secret");
+        assert!(is_authorized(token, Some("Bearer secret")));
+
+        assert!(!is_authorized(token, None));
+        assert!(!is_authorized(token, Some("")));
+        assert!(!is_authorized(token, Some("secret")));
+        assert!(!is_authorized(token, Some("Basic secret")));
+        assert!(!is_authorized(token, Some("bearer secret")));
+        assert!(!is_authorized(token, Some("Bearer wrong!")));
+        assert!(!is_authorized(token, Some("Bearer secre")));
+        assert!(!is_authorized(token, Some("Bearer secret ")));
+        assert!(!is_authorized(token, Some("Bearer ")));
+
+        // Without a configured token, nothing gets in.
+        assert!(!is_authorized(None, Some("Bearer secret")));
+        assert!(!is_authorized(None, Some("Bearer ")));
+        assert!(!is_authorized(None, None));
+    }
+}
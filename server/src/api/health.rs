@@ -0,0 +1,82 @@
+//! Health checks for load balancers and orchestrators.
+//!
+//! `/healthz` only says that the process is up.  `/readyz` says whether submissions sent to us now will be written,
+//! and explains itself in the body either way.
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+use warp::Filter;
+
+use crate::writer::WriterThread;
+
+/// How long to give the database to answer before calling it unreachable.
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+struct Readiness {
+    /// Whether we can get a connection from the writers' pool and run a query.
+    database: bool,
+
+    /// Whether the uuid cache is loaded and listening for changes.  The writer loads it before starting, then a task
+    /// keeps it up to date; while that task is disconnected, new applications aren't picked up.
+    uuid_cache: bool,
+
+    workers_alive: usize,
+    queue_len: usize,
+    ready_queue_limit: usize,
+    shutting_down: bool,
+}
+
+impl Readiness {
+    fn is_ready(&self) -> bool {
+        self.database
+            && self.uuid_cache
+            && self.workers_alive > 0
+            && self.queue_len < self.ready_queue_limit
+            && !self.shutting_down
+    }
+}
+
+async fn readyz(writer: Arc<WriterThread>) -> Response {
+    let database = match writer.check_database(DATABASE_TIMEOUT).await {
+        Ok(()) => true,
+        Err(e) => {
+            only_every::only_every!(Duration::from_secs(30), {
+                log::warn!("Readiness check couldn't reach the database: {:?}", e);
+            });
+            false
+        }
+    };
+
+    let readiness = Readiness {
+        database,
+        uuid_cache: writer.uuid_cache_listening(),
+        workers_alive: writer.workers_alive(),
+        queue_len: writer.queue_len(),
+        ready_queue_limit: writer.ready_queue_limit(),
+        shutting_down: writer.is_shutting_down(),
+    };
+
+    let status = if readiness.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    warp::reply::with_status(warp::reply::json(&readiness), status).into_response()
+}
+
+pub fn routes(
+    writer: Arc<WriterThread>,
+) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    let healthz = warp::path!("healthz")
+        .and(warp::get())
+        .map(|| "ok".into_response());
+    let readyz = warp::path!("readyz")
+        .and(warp::get())
+        .then(move || readyz(writer.clone()));
+
+    healthz.or(readyz).unify()
+}
@@ -0,0 +1,27 @@
+//! Serves [crate::metrics] for Prometheus to scrape.
+use std::sync::Arc;
+
+use warp::reply::{Reply, Response};
+use warp::Filter;
+
+use crate::writer::WriterThread;
+
+fn metrics(writer: &WriterThread) -> Response {
+    crate::metrics::QUEUE_DEPTH.set(writer.queue_len() as i64);
+    warp::reply::with_header(
+        crate::metrics::render(),
+        "Content-Type",
+        prometheus::TEXT_FORMAT,
+    )
+    .into_response()
+}
+
+pub fn routes(
+    writer: Arc<WriterThread>,
+) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    crate::metrics::init();
+
+    warp::path!("metrics")
+        .and(warp::get())
+        .map(move || metrics(&writer))
+}
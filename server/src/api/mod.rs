@@ -1 +1,7 @@
+pub mod admin;
+pub mod health;
+pub mod metrics;
+pub mod reports;
+pub mod submit;
 pub mod submit_v1;
+pub mod submit_v2;
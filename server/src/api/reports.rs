@@ -0,0 +1,115 @@
+//! JSON reports of estimated user counts.  See [crate::reports].
+//!
+//! Unlike the rendered site, these aren't folded or thresholded, so they require the admin token in the same way as
+//! [super::admin].
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::reply::{Reply, Response};
+use warp::Filter;
+
+use crate::reports::{Granularity, ReportQuery, ReportRow};
+
+/// How many days we report on if the caller doesn't say.
+const DEFAULT_RANGE_DAYS: i64 = 30;
+
+#[derive(Deserialize)]
+pub struct ReportParams {
+    application: Option<Uuid>,
+
+    /// First day to include.
+    from: Option<NaiveDate>,
+
+    /// Last day to include.
+    to: Option<NaiveDate>,
+
+    #[serde(default)]
+    granularity: Granularity,
+}
+
+#[derive(Serialize)]
+struct ReportResponse {
+    table: &'static str,
+    application: Option<Uuid>,
+    from: NaiveDate,
+    to: NaiveDate,
+    rows: Vec<ReportRow>,
+}
+
+fn start_of_day(day: NaiveDate) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&day.and_hms_opt(0, 0, 0).expect("Midnight always exists"))
+}
+
+fn status(code: StatusCode) -> Response {
+    warp::reply::with_status(warp::reply::reply(), code).into_response()
+}
+
+async fn report_fallible(pool: &Pool, table: &str, params: ReportParams) -> Result<Response> {
+    let table = match crate::reports::find_table(table) {
+        Some(t) => t,
+        None => return Ok(status(StatusCode::NOT_FOUND)),
+    };
+
+    let to = params.to.unwrap_or_else(|| Utc::now().naive_utc().date());
+    let from = params
+        .from
+        .unwrap_or_else(|| to - chrono::Duration::days(DEFAULT_RANGE_DAYS - 1));
+    if from > to {
+        return Ok(status(StatusCode::BAD_REQUEST));
+    }
+
+    let query = ReportQuery {
+        application: params.application,
+        columns: table.columns.iter().collect(),
+        filters: vec![],
+        from: start_of_day(from),
+        to: start_of_day(to) + chrono::Duration::days(1),
+        granularity: params.granularity,
+    };
+    let client = pool.get().await?;
+    let rows = crate::reports::run_report(&client, table, &query).await?;
+
+    Ok(warp::reply::json(&ReportResponse {
+        table: table.name,
+        application: params.application,
+        from,
+        to,
+        rows,
+    })
+    .into_response())
+}
+
+async fn report(pool: Pool, authorized: bool, table: String, params: ReportParams) -> Response {
+    if !authorized {
+        return status(StatusCode::UNAUTHORIZED);
+    }
+
+    match report_fallible(&pool, &table, params).await {
+        Ok(r) => r,
+        Err(e) => {
+            only_every::only_every!(Duration::from_secs(3), {
+                log::error!("Could not build report because {:?}", e);
+            });
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+pub fn routes(
+    pool: Pool,
+    token: Option<String>,
+) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    warp::path!("reports" / String)
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<ReportParams>())
+        .then(move |table, header: Option<String>, params| {
+            let authorized = super::admin::is_authorized(token.as_deref(), header.as_deref());
+            report(pool.clone(), authorized, table, params)
+        })
+}
@@ -0,0 +1,96 @@
+//! What submissions of every payload version have in common.
+//!
+//! Each version's route parses its own payload and converts it to a [PayloadV2], which is all the writer deals with.
+use std::time::Duration;
+
+use anyhow::Result;
+use bytes::Bytes;
+use serde::{Deserialize, Deserializer};
+use uuid::Uuid;
+
+use hwsurvey_payloads::PayloadV2;
+
+use crate::metrics::{REJECTED_BAD_COUNTRY, REJECTED_BAD_JSON, SUBMISSIONS_REJECTED};
+use crate::writer::{QueueFull, WriterThread};
+
+fn deserialize_uuid<'de, D>(deserializer: D) -> Result<Uuid, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    let s: String = String::deserialize(deserializer)?;
+    Uuid::parse_str(&s).map_err(|_| D::Error::custom("Not a valid uuid"))
+}
+
+#[derive(serde::Deserialize)]
+pub struct Qparams {
+    #[serde(deserialize_with = "deserialize_uuid")]
+    pub token: Uuid,
+}
+
+/// Parse a payload, counting it as rejected if it's bad.
+pub fn parse_payload<T: serde::de::DeserializeOwned>(body: &Bytes) -> Result<T> {
+    match serde_json::from_slice(&body[..]) {
+        Ok(p) => Ok(p),
+        Err(e) => {
+            SUBMISSIONS_REJECTED
+                .with_label_values(&[REJECTED_BAD_JSON])
+                .inc();
+            Err(e.into())
+        }
+    }
+}
+
+/// Queue a payload for the writer.
+pub fn submit(
+    writer: &WriterThread,
+    token: Uuid,
+    ip: Option<String>,
+    country: Option<String>,
+    payload: PayloadV2,
+) -> Result<()> {
+    // The writer would refuse this too, but checking here means it's counted as a rejection rather than a failed write.
+    if let Some(c) = country.as_deref().filter(|c| c.len() != 2) {
+        SUBMISSIONS_REJECTED
+            .with_label_values(&[REJECTED_BAD_COUNTRY])
+            .inc();
+        anyhow::bail!("Got country code {} which is invalid", c);
+    }
+
+    let work = crate::writer::WorkItem {
+        token,
+        ip,
+        country,
+        payload,
+        received_at: chrono::Utc::now(),
+    };
+
+    writer.send(work)?;
+    Ok(())
+}
+
+/// Turn the result of handling a submission into a response.
+pub fn reply(result: Result<()>) -> impl warp::reply::Reply {
+    let status = match result {
+        Ok(_) => warp::http::StatusCode::OK,
+        // This one is on us rather than the client, and telling them lets them try again later.
+        Err(e) if e.is::<QueueFull>() => {
+            only_every::only_every!(Duration::from_secs(3), {
+                log::error!("Turning away submissions because the writer's queue is full");
+            });
+            warp::http::StatusCode::SERVICE_UNAVAILABLE
+        }
+        Err(e) => {
+            only_every::only_every!(Duration::from_secs(3), {
+                log::error!("Could not handle reporting request because {:?}", e);
+            });
+
+            // We really don't want to leak anything to users because users are very untrusted and if someone is going
+            // to find a way to crash us they will, so just claim nothing happened.
+            warp::http::StatusCode::BAD_REQUEST
+        }
+    };
+
+    warp::reply::with_status(warp::reply::reply(), status)
+}
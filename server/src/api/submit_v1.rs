@@ -1,49 +1,40 @@
 use std::sync::Arc;
-use std::time::Duration;
 
 use anyhow::Result;
 use bytes::Bytes;
-use serde::{Deserialize, Deserializer};
-use uuid::Uuid;
 
-use hwsurvey_payloads::PayloadV1;
+use hwsurvey_payloads::cpu::Cpu;
+use hwsurvey_payloads::{PayloadV1, PayloadV2};
 
+use crate::api::submit::{self, Qparams};
 use crate::writer::WriterThread;
 
-pub async fn submit_v1_fallible(
+/// V1 payloads always have every section, so this can't lose anything.
+pub fn convert(payload: PayloadV1) -> PayloadV2 {
+    PayloadV2 {
+        version: Default::default(),
+        machine_id: payload.machine_id,
+        os: payload.os,
+        cpu: Some(Cpu {
+            manufacturer: payload.simdsp.cpu_manufacturer,
+            architecture: payload.simdsp.cpu_architecture,
+            caches: Some(payload.simdsp.cache_info),
+            capabilities: Some(payload.simdsp.cpu_capabilities),
+            arm_capabilities: None,
+        }),
+        memory: Some(payload.memory),
+    }
+}
+
+fn submit_v1_fallible(
     writer: &WriterThread,
-    token: uuid::Uuid,
+    qparams: Qparams,
     ip: Option<String>,
     country: Option<String>,
     body: Bytes,
 ) -> Result<()> {
-    let payload: PayloadV1 = serde_json::from_slice(&body[..])?;
-    let work = crate::writer::WorkItem {
-        token,
-        ip,
-        country,
-        payload,
-        received_at: chrono::Utc::now(),
-    };
-
-    writer.send(work)?;
-    Ok(())
-}
-
-fn deserialize_uuid<'de, D>(deserializer: D) -> Result<Uuid, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    use serde::de::Error;
-
-    let s: String = String::deserialize(deserializer)?;
-    Uuid::parse_str(&s).map_err(|_| D::Error::custom("Not a valid uuid"))
-}
-
-#[derive(serde::Deserialize)]
-pub struct Qparams {
-    #[serde(deserialize_with = "deserialize_uuid")]
-    token: Uuid,
+    let payload: PayloadV1 = submit::parse_payload(&body)?;
+    submit::submit(writer, qparams.token, ip, country, convert(payload))
 }
 
 pub async fn submit_v1(
@@ -53,18 +44,72 @@ pub async fn submit_v1(
     country: Option<String>,
     body: Bytes,
 ) -> impl warp::reply::Reply {
-    let status = match submit_v1_fallible(&*writer, qparams.token, ip, country, body).await {
-        Ok(_) => warp::http::StatusCode::OK,
-        Err(e) => {
-            only_every::only_every!(Duration::from_secs(3), {
-                log::error!("Could not handle reporting request because {:?}", e);
-            });
+    submit::reply(submit_v1_fallible(&writer, qparams, ip, country, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert() {
+        let v1: PayloadV1 = serde_json::from_str(
+            r#"{
+                "simdsp": {
+                    "cpu_manufacturer": "amd",
+                    "cpu_architecture": "x86",
+                    "cache_info": {"l1i": 32768, "l1d": 32768, "l1u": 0, "l2i": 0, "l2d": 0, "l2u": 524288,
+                        "l3i": 0, "l3d": 0, "l3u": 33554432},
+                    "cpu_capabilities": {"x86_sse2": true, "x86_sse3": true, "x86_ssse3": true,
+                        "x86_sse4_1": true, "x86_popcnt_insn": true, "x86_avx": true, "x86_avx2": true,
+                        "x86_fma3": true, "x86_fma4": false, "x86_xop": false, "x86_avx512f": true,
+                        "x86_avx512bw": true, "x86_avx512dq": false, "x86_avx512vl": true}
+                },
+                "memory": {"total": 34359738368},
+                "os": "linux",
+                "machine_id": "abc"
+            }"#,
+        )
+        .unwrap();
+        let v2 = convert(v1);
+
+        assert_eq!(v2.machine_id, "abc");
+        assert_eq!(v2.os, "linux");
+        assert_eq!(v2.memory.unwrap().total, 34359738368);
+
+        let cpu = v2.cpu.unwrap();
+        assert_eq!(cpu.manufacturer, "amd");
+        assert_eq!(cpu.architecture, "x86");
+        assert!(cpu.arm_capabilities.is_none());
 
-            // We really don't want to leak anything to users because users are very untrusted and if someone is going
-            // to find a way to crash us they will, so just claim nothing happened.
-            warp::http::StatusCode::BAD_REQUEST
-        }
-    };
+        let c = cpu.caches.unwrap();
+        assert_eq!(
+            [c.l1i, c.l1d, c.l1u, c.l2i, c.l2d, c.l2u, c.l3i, c.l3d, c.l3u],
+            [32768, 32768, 0, 0, 0, 524288, 0, 0, 33554432]
+        );
 
-    warp::reply::with_status(warp::reply::reply(), status)
+        let c = cpu.capabilities.unwrap();
+        assert_eq!(
+            [
+                c.x86_sse2,
+                c.x86_sse3,
+                c.x86_ssse3,
+                c.x86_sse4_1,
+                c.x86_popcnt_insn,
+                c.x86_avx,
+                c.x86_avx2,
+                c.x86_fma3,
+                c.x86_fma4,
+                c.x86_xop,
+                c.x86_avx512f,
+                c.x86_avx512bw,
+                c.x86_avx512dq,
+                c.x86_avx512vl,
+            ],
+            [
+                true, true, true, true, true, true, true, true, false, false, true, true, false,
+                true
+            ]
+        );
+    }
 }
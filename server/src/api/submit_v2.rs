@@ -0,0 +1,20 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use hwsurvey_payloads::PayloadV2;
+
+use crate::api::submit::{self, Qparams};
+use crate::writer::WriterThread;
+
+pub async fn submit_v2(
+    writer: Arc<WriterThread>,
+    qparams: Qparams,
+    ip: Option<String>,
+    country: Option<String>,
+    body: Bytes,
+) -> impl warp::reply::Reply {
+    let result = submit::parse_payload::<PayloadV2>(&body)
+        .and_then(|payload| submit::submit(&writer, qparams.token, ip, country, payload));
+    submit::reply(result)
+}
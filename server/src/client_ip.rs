@@ -0,0 +1,277 @@
+//! Working out the client's IP when we're behind proxies.
+//!
+//! Headers naming the client are only believed when they come from a trusted proxy, since anyone can send them.  No
+//! proxies are trusted unless they're configured, in which case we use the address of whoever connected.  With
+//! `X-Forwarded-For` and `Forwarded`, each proxy appends the address it got the request from, so we walk the list from
+//! the end and take the first address which isn't one of our proxies.
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use ipnet::IpNet;
+use warp::http::HeaderMap;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const FORWARDED: &str = "forwarded";
+
+pub struct ClientIpResolver {
+    /// Header holding just the client's IP, e.g. Cloudflare's `CF-Connecting-IP`.  Preferred over the forwarding
+    /// headers.
+    ip_header: String,
+
+    /// Peers whose headers we believe.
+    trusted_proxies: Vec<IpNet>,
+}
+
+/// Addresses of IPv4 clients of a dual-stack socket look like `::ffff:1.2.3.4`.  Turn those back into IPv4 so they
+/// match IPv4 networks and are written the way the client would know them.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Parse one hop of a forwarding header, which may have a port and, in `Forwarded`, brackets around IPv6.
+///
+/// Returns `None` for anything else, including the `unknown` and obfuscated identifiers `Forwarded` allows.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    let ip = if let Some(rest) = node.strip_prefix('[') {
+        rest.split(']').next()?.parse().ok()
+    } else {
+        node.parse()
+            .ok()
+            .or_else(|| node.rsplit_once(':')?.0.parse().ok())
+    };
+    ip.map(canonical)
+}
+
+/// The `for=` of each element of a `Forwarded` header, in order.
+fn parse_forwarded(value: &str) -> Vec<Option<IpAddr>> {
+    value
+        .split(',')
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_node(value))
+            })
+        })
+        .collect()
+}
+
+/// Every hop of a header, across all of its lines, in order.
+fn hops(
+    headers: &HeaderMap,
+    name: &str,
+    parse: impl Fn(&str) -> Vec<Option<IpAddr>>,
+) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(name)
+        .iter()
+        .flat_map(|v| match v.to_str() {
+            Ok(s) => parse(s),
+            Err(_) => vec![None],
+        })
+        .collect()
+}
+
+impl ClientIpResolver {
+    pub fn new(ip_header: String, trusted_proxies: Vec<IpNet>) -> ClientIpResolver {
+        ClientIpResolver {
+            ip_header,
+            trusted_proxies,
+        }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|n| n.contains(&ip))
+    }
+
+    /// Whether headers from whoever connected should be believed.
+    pub fn is_trusted_peer(&self, peer: Option<SocketAddr>) -> bool {
+        peer.is_some_and(|p| self.is_trusted(canonical(p.ip())))
+    }
+
+    /// Work out the client's IP from the address of whoever connected and the request's headers.
+    ///
+    /// Returns `None` only if there are no usable headers and warp couldn't tell us who connected.
+    pub fn resolve(&self, peer: Option<SocketAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let peer_ip = peer.map(|x| canonical(x.ip()));
+
+        if !self.is_trusted_peer(peer) {
+            let names = [self.ip_header.as_str(), X_FORWARDED_FOR, FORWARDED];
+            // Without any proxies configured, headers are expected to be ignored, so there's nothing to warn about.
+            let configured = !self.trusted_proxies.is_empty();
+            if let Some(name) = names
+                .iter()
+                .find(|n| configured && headers.contains_key(**n))
+            {
+                only_every::only_every!(
+                    Duration::from_secs(30),
+                    log::warn!(
+                        "Ignoring the {} header from {}, which is not in proxy.trusted_proxies",
+                        name,
+                        peer_ip.map_or_else(|| "an unknown peer".to_string(), |x| x.to_string())
+                    )
+                );
+            }
+            return peer_ip;
+        }
+
+        if let Some(value) = headers.get(self.ip_header.as_str()) {
+            match value.to_str().ok().and_then(parse_node) {
+                Some(ip) => return Some(ip),
+                None => only_every::only_every!(
+                    Duration::from_secs(30),
+                    log::warn!("Unable to parse the {} header {:?}", self.ip_header, value)
+                ),
+            }
+        }
+
+        // Forwarded is the standard one, so if a proxy bothered to send it, it's probably the better of the two.
+        let mut chain = hops(headers, FORWARDED, parse_forwarded);
+        if chain.is_empty() {
+            chain = hops(headers, X_FORWARDED_FOR, |v| {
+                v.split(',').map(parse_node).collect()
+            });
+        }
+
+        if chain.is_empty() {
+            only_every::only_every!(
+                Duration::from_secs(30),
+                log::warn!(
+                    "Unable to get IP from the {}, {}, or {} headers. Falling back to remote addr",
+                    self.ip_header,
+                    X_FORWARDED_FOR,
+                    FORWARDED
+                )
+            );
+            return peer_ip;
+        }
+
+        // Walk back from the proxy nearest us.  If a hop can't be parsed, whoever added it is on the client's side of
+        // our proxies but we can't say who they are, so settle for the last address we know.
+        let mut client = peer_ip;
+        for hop in chain.into_iter().rev() {
+            match hop {
+                Some(ip) => {
+                    client = Some(ip);
+                    if !self.is_trusted(ip) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(trusted: &[&str], peer: &str, headers: &[(&str, &str)]) -> Option<IpAddr> {
+        let trusted = trusted.iter().map(|x| x.parse().unwrap()).collect();
+        let mut map = HeaderMap::new();
+        for (k, v) in headers {
+            map.append(
+                warp::http::header::HeaderName::from_bytes(k.as_bytes()).unwrap(),
+                v.parse().unwrap(),
+            );
+        }
+        ClientIpResolver::new("CF-Connecting-IP".to_string(), trusted)
+            .resolve(Some(peer.parse().unwrap()), &map)
+    }
+
+    fn ip(x: &str) -> Option<IpAddr> {
+        Some(x.parse().unwrap())
+    }
+
+    #[test]
+    fn test_parse_forwarded() {
+        assert_eq!(
+            parse_forwarded(
+                r#"for=192.0.2.60;proto=http;by=203.0.113.43, For="[2001:db8:cafe::17]:4711""#
+            ),
+            vec![ip("192.0.2.60"), ip("2001:db8:cafe::17")]
+        );
+        assert_eq!(
+            parse_forwarded("for=unknown, proto=https, for=_hidden"),
+            vec![None, None]
+        );
+        assert_eq!(parse_node("198.51.100.1:8080"), ip("198.51.100.1"));
+        assert_eq!(parse_node("::ffff:198.51.100.1"), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn test_untrusted_peer() {
+        let trusted = &["10.0.0.0/8"][..];
+        let headers = [
+            ("X-Forwarded-For", "198.51.100.1"),
+            ("CF-Connecting-IP", "198.51.100.2"),
+        ];
+        assert_eq!(
+            resolve(trusted, "203.0.113.9:1234", &headers),
+            ip("203.0.113.9")
+        );
+        assert_eq!(
+            resolve(trusted, "10.1.2.3:1234", &headers),
+            ip("198.51.100.2")
+        );
+        // Without a list, no one is trusted.
+        assert_eq!(
+            resolve(&[], "203.0.113.9:1234", &headers),
+            ip("203.0.113.9")
+        );
+        assert_eq!(resolve(&[], "10.1.2.3:1234", &headers), ip("10.1.2.3"));
+    }
+
+    #[test]
+    fn test_is_trusted_peer() {
+        let resolver = ClientIpResolver::new(String::new(), vec!["10.0.0.0/8".parse().unwrap()]);
+        assert!(resolver.is_trusted_peer(Some("10.1.2.3:1234".parse().unwrap())));
+        assert!(resolver.is_trusted_peer(Some("[::ffff:10.1.2.3]:1234".parse().unwrap())));
+        assert!(!resolver.is_trusted_peer(Some("203.0.113.9:1234".parse().unwrap())));
+        assert!(!resolver.is_trusted_peer(None));
+        assert!(!ClientIpResolver::new(String::new(), vec![])
+            .is_trusted_peer(Some("10.1.2.3:1234".parse().unwrap())));
+    }
+
+    #[test]
+    fn test_walks_past_trusted_proxies() {
+        let trusted = &["10.0.0.0/8", "2001:db8::/32"][..];
+
+        // The client claimed to be 1.1.1.1, but the nearest untrusted hop is who actually connected to our proxies.
+        assert_eq!(
+            resolve(
+                trusted,
+                "10.0.0.1:1234",
+                &[
+                    ("X-Forwarded-For", "1.1.1.1, 198.51.100.1"),
+                    ("X-Forwarded-For", "10.0.0.2")
+                ]
+            ),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            resolve(
+                trusted,
+                "[::ffff:10.0.0.1]:1234",
+                &[("Forwarded", r#"for=198.51.100.1, for="[2001:db8::1]""#)]
+            ),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            resolve(trusted, "10.0.0.1:1234", &[("X-Forwarded-For", "10.0.0.2")]),
+            ip("10.0.0.2")
+        );
+        assert_eq!(
+            resolve(trusted, "10.0.0.1:1234", &[("Forwarded", "for=unknown")]),
+            ip("10.0.0.1")
+        );
+        assert_eq!(resolve(trusted, "10.0.0.1:1234", &[]), ip("10.0.0.1"));
+    }
+}
@@ -0,0 +1,369 @@
+//! Server settings, from a TOML file and the environment.
+//!
+//! Every setting has a default, so the file is optional.  Any setting can be overridden by an environment variable
+//! named `HWSURVEY_<SECTION>__<KEY>`, for example `HWSURVEY_WRITER__QUEUE_SIZE=5000`.  Values are parsed as TOML if
+//! they can be and used as strings otherwise, so `[1, 2]` is an array but `0.0.0.0:10000` is a string.  For
+//! compatibility, `DATABASE_URL` is also honored.
+//!
+//! See `server/config.example.toml` for all the settings.
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::anonymization::BinsConfig;
+
+const ENV_PREFIX: &str = "HWSURVEY_";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub writer: WriterConfig,
+    pub proxy: ProxyConfig,
+    pub reports: ReportsConfig,
+    pub anonymization: BinsConfig,
+    pub geoip: GeoIpConfig,
+    pub admin: AdminConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Addresses to listen on.
+    pub listen: Vec<SocketAddr>,
+
+    /// Largest submission we'll accept, in bytes.
+    pub body_limit: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 10000))],
+            body_limit: 10 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// Required, but usually comes from `DATABASE_URL`.
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WriterConfig {
+    /// Number of writer tasks, each with its own database connection.
+    pub workers: usize,
+
+    /// How many submissions can wait to be written before we start turning them away.
+    pub queue_size: usize,
+
+    /// How often to reload applications, OSes, etc. from the database, in seconds.  Changes made through the admin API
+    /// or which trigger a notification are picked up immediately regardless.
+    pub uuid_cache_refresh_secs: u64,
+
+    /// How long to spend writing queued submissions when asked to shut down, in seconds.
+    pub shutdown_timeout_secs: u64,
+
+    /// Record OSes, CPU manufacturers, and CPU architectures we don't know about but which match this regex, so that
+    /// they can be approved through the admin API.
+    ///
+    /// The whole value must match.  Something like `[a-z0-9_]{1,32}` keeps arbitrary client-provided text out of the
+    /// database.
+    pub dimension_allowlist: Option<String>,
+
+    /// Written in place of the IP when we couldn't work one out.
+    pub unknown_ip: String,
+
+    /// Written in place of the country when we couldn't work one out.  The default matches Cloudflare's value from
+    /// https://developers.cloudflare.com/fundamentals/get-started/reference/http-request-headers/
+    pub unknown_country: String,
+}
+
+impl Default for WriterConfig {
+    fn default() -> WriterConfig {
+        WriterConfig {
+            workers: 4,
+            queue_size: 1000,
+            uuid_cache_refresh_secs: 300,
+            shutdown_timeout_secs: 20,
+            dimension_allowlist: None,
+            unknown_ip: "123.123.123.123".to_string(),
+            unknown_country: "XX".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// Header holding the client's IP, as set by the proxy in front of us.  If it's missing, we try `Forwarded` and
+    /// `X-Forwarded-For`, then use the address of whoever connected.
+    pub ip_header: String,
+
+    /// Networks of the proxies in front of us.  The IP and country headers are ignored unless they come from one of
+    /// these, so by default we trust no one and use the address of whoever connected.
+    pub trusted_proxies: Vec<ipnet::IpNet>,
+
+    /// Header holding the client's two-letter country code.
+    pub country_header: String,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> ProxyConfig {
+        ProxyConfig {
+            ip_header: "CF-Connecting-IP".to_string(),
+            trusted_proxies: vec![],
+            country_header: "CF-IPCountry".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReportsConfig {
+    /// How often to recompute the weekly and monthly rollups, in seconds.
+    pub rollup_interval_secs: u64,
+}
+
+impl Default for ReportsConfig {
+    fn default() -> ReportsConfig {
+        ReportsConfig {
+            rollup_interval_secs: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeoIpConfig {
+    /// MaxMind-format (mmdb) country or city database to look up the country in when the country header is missing.
+    pub database: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Callers of the admin API must send this as `Authorization: Bearer <token>`.  Unset disables the admin API.  Can
+    /// also be given as `HWSURVEY_ADMIN_TOKEN`.
+    pub token: Option<String>,
+}
+
+/// Parse an environment variable's value as a TOML value, falling back to a string.
+fn parse_env_value(raw: &str) -> toml::Value {
+    #[derive(Deserialize)]
+    struct Wrapper {
+        v: toml::Value,
+    }
+
+    toml::from_str::<Wrapper>(&format!("v = {}", raw))
+        .map(|w| w.v)
+        .unwrap_or_else(|_| toml::Value::String(raw.to_string()))
+}
+
+/// Set `path` in `root` to `value`, creating tables along the way.
+fn set_path(root: &mut toml::value::Table, path: &[String], value: toml::Value) -> Result<()> {
+    let (last, tables) = path.split_last().expect("Paths are never empty");
+    let mut current = root;
+    for key in tables {
+        current = current
+            .entry(key.clone())
+            .or_insert_with(|| toml::Value::Table(Default::default()))
+            .as_table_mut()
+            .with_context(|| format!("{} is not a section", key))?;
+    }
+    current.insert(last.clone(), value);
+    Ok(())
+}
+
+impl Config {
+    /// Load the config from `path` if given, then apply overrides from `env`.
+    ///
+    /// `env` is usually [std::env::vars], but is a parameter so the overrides can be tested.
+    pub fn load(
+        path: Option<&Path>,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Config> {
+        let mut root = match path {
+            Some(p) => {
+                let text = std::fs::read_to_string(p)
+                    .with_context(|| format!("Unable to read config file {}", p.display()))?;
+                toml::from_str(&text)
+                    .with_context(|| format!("Unable to parse config file {}", p.display()))?
+            }
+            None => toml::value::Table::new(),
+        };
+
+        for (name, raw) in env {
+            let (path, value): (Vec<String>, _) = if name == "DATABASE_URL" {
+                (
+                    vec!["database".to_string(), "url".to_string()],
+                    parse_env_value(&raw),
+                )
+            } else if name == "HWSURVEY_ADMIN_TOKEN" {
+                // Tokens are always strings, even ones which look like numbers.
+                (
+                    vec!["admin".to_string(), "token".to_string()],
+                    toml::Value::String(raw),
+                )
+            } else {
+                // Other variables without a section aren't settings.
+                match name.strip_prefix(ENV_PREFIX) {
+                    Some(rest) if rest.contains("__") => (
+                        rest.split("__").map(|x| x.to_lowercase()).collect(),
+                        parse_env_value(&raw),
+                    ),
+                    _ => continue,
+                }
+            };
+
+            set_path(&mut root, &path, value)
+                .with_context(|| format!("Unable to apply {}", name))?;
+        }
+
+        let config: Config = toml::Value::Table(root)
+            .try_into()
+            .context("Invalid configuration")?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check everything serde can't, so that mistakes are found at startup rather than when they're first used.
+    fn validate(&self) -> Result<()> {
+        if self.server.listen.is_empty() {
+            anyhow::bail!("server.listen must have at least one address");
+        }
+        if self.server.body_limit == 0 {
+            anyhow::bail!("server.body_limit must be greater than 0");
+        }
+
+        match self.database.url.as_deref() {
+            None => anyhow::bail!("database.url must be set, usually through DATABASE_URL"),
+            Some(url) => {
+                url.parse::<tokio_postgres::Config>()
+                    .context("database.url is invalid")?;
+            }
+        }
+
+        if self.writer.workers == 0 {
+            anyhow::bail!("writer.workers must be greater than 0");
+        }
+        if self.writer.queue_size == 0 {
+            anyhow::bail!("writer.queue_size must be greater than 0");
+        }
+        self.dimension_allowlist()?;
+        if self.writer.unknown_country.len() != 2 {
+            anyhow::bail!("writer.unknown_country must be 2 characters");
+        }
+
+        for (name, header) in [
+            ("proxy.ip_header", &self.proxy.ip_header),
+            ("proxy.country_header", &self.proxy.country_header),
+        ] {
+            warp::http::header::HeaderName::from_bytes(header.as_bytes())
+                .with_context(|| format!("{} is not a valid header name", name))?;
+        }
+
+        if self.reports.rollup_interval_secs == 0 {
+            anyhow::bail!("reports.rollup_interval_secs must be greater than 0");
+        }
+
+        self.anonymization
+            .validate()
+            .context("Invalid anonymization")?;
+
+        if self.admin.token.as_deref().map(str::trim) == Some("") {
+            anyhow::bail!("admin.token must not be empty");
+        }
+        Ok(())
+    }
+
+    pub fn database_url(&self) -> &str {
+        self.database
+            .url
+            .as_deref()
+            .expect("Validated when the config was loaded")
+    }
+
+    /// The dimension allowlist, compiled to match whole values.
+    pub fn dimension_allowlist(&self) -> Result<Option<regex::Regex>> {
+        self.writer
+            .dimension_allowlist
+            .as_deref()
+            .map(|x| regex::Regex::new(&format!("^(?:{})$", x)))
+            .transpose()
+            .context("writer.dimension_allowlist is not a valid regex")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let config = Config::load(
+            None,
+            env(&[
+                ("DATABASE_URL", "host=localhost user=postgres"),
+                ("HWSURVEY_WRITER__QUEUE_SIZE", "5"),
+                ("HWSURVEY_SERVER__LISTEN", r#"["0.0.0.0:1", "[::1]:2"]"#),
+                ("HWSURVEY_PROXY__IP_HEADER", "X-Real-IP"),
+                ("HWSURVEY_PROXY__TRUSTED_PROXIES", r#"["10.0.0.0/8"]"#),
+                ("HWSURVEY_ADMIN_TOKEN", "12345"),
+                ("HWSURVEY_NOT_A_SETTING", "1"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(config.database_url(), "host=localhost user=postgres");
+        assert_eq!(config.writer.queue_size, 5);
+        assert_eq!(
+            config.server.listen,
+            vec![
+                "0.0.0.0:1".parse::<SocketAddr>().unwrap(),
+                "[::1]:2".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.proxy.ip_header, "X-Real-IP");
+        assert_eq!(
+            config.proxy.trusted_proxies,
+            vec!["10.0.0.0/8".parse::<ipnet::IpNet>().unwrap()]
+        );
+        assert_eq!(config.admin.token.as_deref(), Some("12345"));
+        assert_eq!(config.writer.workers, WriterConfig::default().workers);
+    }
+
+    #[test]
+    fn test_validation() {
+        let url = ("DATABASE_URL", "host=localhost");
+
+        assert!(Config::load(None, env(&[])).is_err());
+        assert!(Config::load(None, env(&[url])).is_ok());
+        assert!(Config::load(None, env(&[url, ("HWSURVEY_WRITER__WORKERS", "0")])).is_err());
+        assert!(Config::load(
+            None,
+            env(&[url, ("HWSURVEY_WRITER__UNKNOWN_COUNTRY", "XXX")])
+        )
+        .is_err());
+        assert!(Config::load(None, env(&[url, ("HWSURVEY_WRITER__NOPE", "1")])).is_err());
+        assert!(Config::load(None, env(&[url, ("HWSURVEY_ADMIN__TOKEN", "")])).is_err());
+        assert!(Config::load(
+            None,
+            env(&[url, ("HWSURVEY_ANONYMIZATION__MEMORY", "[2, 1]")])
+        )
+        .is_err());
+    }
+}
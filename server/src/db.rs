@@ -0,0 +1,42 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod, Runtime};
+use tokio_postgres::Client;
+
+/// How long [pool]'s callers wait for a connection before giving up.
+const POOL_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Open a connection for something other than the writer, e.g. rollups.
+///
+/// These are separate from the writer's connection so that they never wait behind a backlog of submissions.  `purpose`
+/// is only used for logging.
+pub async fn connect(db_url: &str, purpose: &'static str) -> Result<Client> {
+    let (client, connection) = tokio_postgres::connect(db_url, tokio_postgres::NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            log::error!("Database connection for {} failed: {:?}", purpose, e);
+        }
+    });
+    Ok(client)
+}
+
+/// A pool of up to `max_size` connections for something other than the writer, e.g. the admin API.
+///
+/// Connections are made on demand and replaced if they break, so unlike [connect] a restarted database only fails the
+/// requests in flight.  Waiting for a connection gives up after a while, so a burst of requests fails rather than
+/// piling up.
+pub fn pool(db_url: &str, max_size: usize) -> Result<Pool> {
+    let manager = Manager::from_config(
+        db_url.parse()?,
+        tokio_postgres::NoTls,
+        ManagerConfig {
+            recycling_method: RecyclingMethod::Fast,
+        },
+    );
+    Ok(Pool::builder(manager)
+        .max_size(max_size)
+        .runtime(Runtime::Tokio1)
+        .wait_timeout(Some(POOL_WAIT_TIMEOUT))
+        .build()?)
+}
@@ -0,0 +1,38 @@
+//! Country lookups from a local MaxMind-format database, for when the proxy in front of us doesn't tell us the country.
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use maxminddb::geoip2;
+
+pub struct GeoIp {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> Result<GeoIp> {
+        let reader = maxminddb::Reader::open_readfile(path)
+            .with_context(|| format!("Unable to open GeoIP database {}", path.display()))?;
+        log::info!(
+            "Loaded GeoIP database {} of type {}, built at {}",
+            path.display(),
+            reader.metadata.database_type,
+            chrono::DateTime::from_timestamp(reader.metadata.build_epoch as i64, 0)
+                .map_or_else(|| "an unknown time".to_string(), |t| t.to_string())
+        );
+        Ok(GeoIp { reader })
+    }
+
+    /// The two-letter country code of `ip`, if the database has one.
+    ///
+    /// Falls back to the country the address is registered to, which is usually but not always the same.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let record = self.reader.lookup::<geoip2::Country>(ip).ok()?;
+        record
+            .country
+            .and_then(|c| c.iso_code)
+            .or_else(|| record.registered_country.and_then(|c| c.iso_code))
+            .filter(|c| c.len() == 2)
+            .map(|c| c.to_ascii_uppercase())
+    }
+}
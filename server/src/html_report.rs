@@ -0,0 +1,854 @@
+//! Renders the reports as a self-contained static HTML site, which can be published without giving anyone access to
+//! the database.
+//!
+//! Every chart is backed by a table with the same numbers, since a good chunk of our audience uses screen readers.
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Duration as CDuration, DurationRound, Utc};
+use tokio_postgres::Client;
+use uuid::Uuid;
+
+use crate::reports::{run_report, Filter, Granularity, ReportQuery, ReportRow};
+
+/// How many categories get a line on a chart.  The rest are still in the table.
+const MAX_CHART_SERIES: usize = 6;
+
+/// The columns of `cpu_caches` which are cache sizes, in the order they're shown.
+const CACHE_COLUMNS: &[&str] = &[
+    "l1i", "l1d", "l1u", "l2i", "l2d", "l2u", "l3i", "l3d", "l3u",
+];
+
+const CHART_WIDTH: f64 = 720.0;
+const CHART_HEIGHT: f64 = 240.0;
+const CHART_PADDING: f64 = 40.0;
+
+/// Colors for chart lines, chosen to stay distinguishable for the common kinds of color blindness.
+const PALETTE: &[&str] = &[
+    "#0072b2", "#e69f00", "#009e73", "#cc79a7", "#56b4e9", "#d55e00",
+];
+
+const STYLE: &str = r#"
+body { font-family: sans-serif; max-width: 60em; margin: 0 auto; padding: 1em; line-height: 1.4; }
+table { border-collapse: collapse; margin: 1em 0; }
+th, td { border: 1px solid #999; padding: 0.25em 0.75em; text-align: left; }
+td.num { text-align: right; }
+.swatch { display: inline-block; width: 1em; height: 1em; margin-right: 0.5em; vertical-align: middle; }
+svg { max-width: 100%; height: auto; }
+"#;
+
+pub struct SiteOptions {
+    pub output: PathBuf,
+
+    /// How many whole weeks, ending with last week, to report on.
+    pub weeks: i64,
+
+    /// Values seen on fewer machines than this over the whole range are folded into "other", and pages or sections
+    /// with fewer machines than this are left out, so that rare hardware doesn't stand out.
+    pub min_users: f64,
+}
+
+/// The range a site covers.
+struct Range {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+
+    /// Start of each week in the range.
+    weeks: Vec<DateTime<Utc>>,
+}
+
+/// Estimated machines per category, over the whole range and per week.
+struct Breakdown {
+    /// Sorted by count, descending.
+    total: Vec<(String, f64)>,
+
+    /// Indexed like [Range::weeks].
+    by_week: HashMap<String, Vec<f64>>,
+
+    /// Machines in any category.  A machine can be in more than one, e.g. if it was upgraded, so this is estimated
+    /// from the union of every category rather than by adding them up.
+    machines: f64,
+
+    /// Like `machines`, for each week.
+    machines_by_week: Vec<f64>,
+}
+
+impl Breakdown {
+    fn total_machines(&self) -> f64 {
+        self.machines
+    }
+
+    fn share_of(&self, category: &str) -> f64 {
+        if self.machines == 0.0 {
+            return 0.0;
+        }
+
+        self.total
+            .iter()
+            .find(|x| x.0 == category)
+            .map_or(0.0, |x| x.1 / self.machines)
+    }
+
+    /// Share of `category` in each week, or `None` for weeks we have no data for.
+    fn weekly_share_of(&self, category: &str) -> Vec<Option<f64>> {
+        self.machines_by_week
+            .iter()
+            .enumerate()
+            .map(|(i, total)| {
+                if *total == 0.0 {
+                    return None;
+                }
+                let mine = self.by_week.get(category).map_or(0.0, |v| v[i]);
+                Some(mine / total)
+            })
+            .collect()
+    }
+
+    /// Fold categories with fewer than `min_users` machines into "other".
+    ///
+    /// "other" is the sum of what it replaces, which can count a machine twice; it's only ever a small share.
+    fn fold_rare(&mut self, min_users: f64) {
+        let (keep, fold): (Vec<_>, Vec<_>) = self.total.drain(..).partition(|x| x.1 >= min_users);
+        self.total = keep;
+        if fold.is_empty() {
+            return;
+        }
+
+        let mut other_weekly = vec![0.0; self.machines_by_week.len()];
+        for (cat, _) in fold.iter() {
+            if let Some(w) = self.by_week.remove(cat) {
+                for (o, v) in other_weekly.iter_mut().zip(w) {
+                    *o += v;
+                }
+            }
+        }
+
+        self.total
+            .push(("other".to_string(), fold.iter().map(|x| x.1).sum()));
+        self.by_week.insert("other".to_string(), other_weekly);
+    }
+}
+
+fn escape(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn slugify(input: &str) -> String {
+    let mut out = String::new();
+    for c in input.chars() {
+        if c.is_ascii_alphanumeric() {
+            out.push(c.to_ascii_lowercase());
+        } else if !out.ends_with('-') {
+            out.push('-');
+        }
+    }
+
+    let trimmed = out.trim_matches('-');
+    if trimmed.is_empty() {
+        "application".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+fn format_bytes(bytes: i64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if value.fract() == 0.0 {
+        format!("{} {}", value, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_percent(share: f64) -> String {
+    format!("{:.1}%", share * 100.0)
+}
+
+/// Fetch a breakdown of one table by `columns`, counting only rows where none of them are null and each of `equals`
+/// (a column and value) matches.  `label` turns a row into its category, or `None` to skip the row.
+async fn fetch_breakdown(
+    client: &Client,
+    table: &str,
+    columns: &[&str],
+    equals: &[(&str, &str)],
+    application: Option<Uuid>,
+    range: &Range,
+    label: impl Fn(&ReportRow) -> Option<String>,
+) -> Result<Breakdown> {
+    let table = crate::reports::find_table(table).expect("Tables are hard-coded");
+    let column = |name: &str| {
+        table
+            .columns_named(&[name])
+            .expect("Columns are hard-coded")[0]
+    };
+    let filters = || {
+        columns
+            .iter()
+            .map(|c| Filter::NotNull(column(c)))
+            .chain(
+                equals
+                    .iter()
+                    .map(|(c, v)| Filter::Equals(column(c), v.to_string())),
+            )
+            .collect::<Vec<_>>()
+    };
+    let query = |columns: &[&str], granularity| ReportQuery {
+        application,
+        columns: columns.iter().map(|c| column(c)).collect(),
+        filters: filters(),
+        from: range.from,
+        to: range.to,
+        granularity,
+    };
+    let week_index = |row: &ReportRow| range.weeks.iter().position(|w| Some(*w) == row.period);
+
+    let mut totals: HashMap<String, f64> = Default::default();
+    for row in run_report(client, table, &query(columns, Granularity::Total)).await? {
+        if let Some(cat) = label(&row) {
+            *totals.entry(cat).or_default() += row.users_by_id.unwrap_or(0.0);
+        }
+    }
+
+    // With no columns to group by, the report is the union of every row.
+    let machines = run_report(client, table, &query(&[], Granularity::Total))
+        .await?
+        .first()
+        .and_then(|r| r.users_by_id)
+        .unwrap_or(0.0);
+
+    // Weekly counts come from the rollups, so a machine reporting every day of a week is counted once.
+    let mut by_week: HashMap<String, Vec<f64>> = Default::default();
+    for row in run_report(client, table, &query(columns, Granularity::Week)).await? {
+        if let (Some(cat), Some(i)) = (label(&row), week_index(&row)) {
+            by_week
+                .entry(cat)
+                .or_insert_with(|| vec![0.0; range.weeks.len()])[i] +=
+                row.users_by_id.unwrap_or(0.0);
+        }
+    }
+
+    let mut machines_by_week = vec![0.0; range.weeks.len()];
+    for row in run_report(client, table, &query(&[], Granularity::Week)).await? {
+        if let Some(i) = week_index(&row) {
+            machines_by_week[i] = row.users_by_id.unwrap_or(0.0);
+        }
+    }
+
+    let mut total: Vec<(String, f64)> = totals.into_iter().collect();
+    total.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    Ok(Breakdown {
+        total,
+        by_week,
+        machines,
+        machines_by_week,
+    })
+}
+
+fn label_text(column: &'static str) -> impl Fn(&ReportRow) -> Option<String> {
+    move |row| row.values.get(column)?.as_str().map(|x| x.to_string())
+}
+
+fn label_bytes(column: &'static str) -> impl Fn(&ReportRow) -> Option<String> {
+    move |row| row.values.get(column)?.as_i64().map(format_bytes)
+}
+
+fn label_flag(column: &'static str) -> impl Fn(&ReportRow) -> Option<String> {
+    move |row| {
+        let value = row.values.get(column)?.as_bool()?;
+        Some(if value { "yes" } else { "no" }.to_string())
+    }
+}
+
+/// Render a line chart of shares over time.  Each series is a name and a share per week.
+fn render_chart(title: &str, range: &Range, series: &[(String, Vec<Option<f64>>)]) -> String {
+    let mut out = String::new();
+    let plot_width = CHART_WIDTH - 2.0 * CHART_PADDING;
+    let plot_height = CHART_HEIGHT - 2.0 * CHART_PADDING;
+    let step = plot_width / (range.weeks.len().max(2) - 1) as f64;
+
+    let x = |i: usize| CHART_PADDING + i as f64 * step;
+    let y = |v: f64| CHART_HEIGHT - CHART_PADDING - v * plot_height;
+
+    // The tables next to charts carry the same data, so screen readers get the title and can skip the drawing.
+    write!(
+        out,
+        r#"<svg role="img" viewBox="0 0 {CHART_WIDTH} {CHART_HEIGHT}" width="{CHART_WIDTH}" height="{CHART_HEIGHT}"><title>{}</title>"#,
+        escape(title)
+    )
+    .unwrap();
+
+    for pct in [0.0, 0.25, 0.5, 0.75, 1.0] {
+        write!(
+            out,
+            r##"<line x1="{}" x2="{}" y1="{y}" y2="{y}" stroke="#ddd"/><text x="{}" y="{}" font-size="10" text-anchor="end">{}%</text>"##,
+            CHART_PADDING,
+            CHART_WIDTH - CHART_PADDING,
+            CHART_PADDING - 4.0,
+            y(pct) + 3.0,
+            pct * 100.0,
+            y = y(pct),
+        )
+        .unwrap();
+    }
+
+    if let (Some(first), Some(last)) = (range.weeks.first(), range.weeks.last()) {
+        write!(
+            out,
+            r#"<text x="{}" y="{}" font-size="10">{}</text><text x="{}" y="{}" font-size="10" text-anchor="end">{}</text>"#,
+            CHART_PADDING,
+            CHART_HEIGHT - CHART_PADDING / 2.0,
+            first.format("%Y-%m-%d"),
+            CHART_WIDTH - CHART_PADDING,
+            CHART_HEIGHT - CHART_PADDING / 2.0,
+            last.format("%Y-%m-%d"),
+        )
+        .unwrap();
+    }
+
+    for ((_, values), color) in series.iter().zip(PALETTE.iter().cycle()) {
+        // Weeks without data break the line rather than being drawn as 0.
+        let mut points = String::new();
+        let flush = |points: &mut String, out: &mut String| {
+            if !points.is_empty() {
+                write!(
+                    out,
+                    r#"<polyline fill="none" stroke="{color}" stroke-width="2" points="{points}"/>"#
+                )
+                .unwrap();
+                points.clear();
+            }
+        };
+
+        for (i, v) in values.iter().enumerate() {
+            match v {
+                Some(v) => write!(points, "{:.1},{:.1} ", x(i), y(*v)).unwrap(),
+                None => flush(&mut points, &mut out),
+            }
+        }
+        flush(&mut points, &mut out);
+    }
+
+    out.push_str("</svg><ul>");
+    for ((name, _), color) in series.iter().zip(PALETTE.iter().cycle()) {
+        write!(
+            out,
+            r#"<li><span class="swatch" style="background: {color}"></span>{}</li>"#,
+            escape(name)
+        )
+        .unwrap();
+    }
+    out.push_str("</ul>");
+
+    out
+}
+
+fn render_share_table(caption: &str, value_header: &str, breakdown: &Breakdown) -> String {
+    let total = breakdown.total_machines();
+    let mut out = String::new();
+
+    write!(
+        out,
+        r#"<table><caption>{}</caption><thead><tr><th scope="col">{}</th><th scope="col">Machines (estimated)</th><th scope="col">Share</th></tr></thead><tbody>"#,
+        escape(caption),
+        escape(value_header)
+    )
+    .unwrap();
+
+    for (cat, count) in breakdown.total.iter() {
+        write!(
+            out,
+            r#"<tr><th scope="row">{}</th><td class="num">{:.0}</td><td class="num">{}</td></tr>"#,
+            escape(cat),
+            count,
+            format_percent(count / total)
+        )
+        .unwrap();
+    }
+
+    out.push_str("</tbody></table>");
+    out
+}
+
+/// Render a breakdown as a table and a chart of its top categories.
+fn render_distribution(
+    heading: &str,
+    value_header: &str,
+    range: &Range,
+    min_users: f64,
+    mut breakdown: Breakdown,
+) -> String {
+    if breakdown.total_machines() < min_users {
+        return format!("<h3>{}</h3><p>Not enough data yet.</p>", escape(heading));
+    }
+
+    breakdown.fold_rare(min_users);
+
+    let series: Vec<(String, Vec<Option<f64>>)> = breakdown
+        .total
+        .iter()
+        .take(MAX_CHART_SERIES)
+        .map(|(cat, _)| (cat.clone(), breakdown.weekly_share_of(cat)))
+        .collect();
+
+    format!(
+        "<h3>{}</h3>{}{}",
+        escape(heading),
+        render_share_table(heading, value_header, &breakdown),
+        render_chart(&format!("{} over time", heading), range, &series)
+    )
+}
+
+async fn render_simd_section(
+    client: &Client,
+    application: Option<Uuid>,
+    range: &Range,
+    min_users: f64,
+) -> Result<String> {
+    let flags = crate::reports::find_table("cpu_capabilities")
+        .expect("Tables are hard-coded")
+        .columns
+        .iter()
+        .filter(|c| c.name.starts_with("x86_"));
+
+    let mut rows = vec![];
+    for flag in flags {
+        let breakdown = fetch_breakdown(
+            client,
+            "cpu_capabilities",
+            &[flag.name],
+            &[("architecture", "x86")],
+            application,
+            range,
+            label_flag(flag.name),
+        )
+        .await?;
+
+        if breakdown.total_machines() < min_users {
+            continue;
+        }
+
+        rows.push((flag.name, breakdown));
+    }
+
+    let mut out = String::from("<h2>SIMD features</h2>");
+    if rows.is_empty() {
+        out.push_str("<p>Not enough data yet.</p>");
+        return Ok(out);
+    }
+
+    out.push_str(
+        r#"<table><caption>Share of x86 machines with each feature</caption><thead><tr><th scope="col">Feature</th><th scope="col">x86 machines (estimated)</th><th scope="col">Share with feature</th></tr></thead><tbody>"#,
+    );
+    for (name, breakdown) in rows.iter() {
+        write!(
+            out,
+            r#"<tr><th scope="row">{}</th><td class="num">{:.0}</td><td class="num">{}</td></tr>"#,
+            escape(name.trim_start_matches("x86_")),
+            breakdown.total_machines(),
+            format_percent(breakdown.share_of("yes"))
+        )
+        .unwrap();
+    }
+    out.push_str("</tbody></table>");
+
+    // One chart with every feature gets unreadable, so chart the ones which are interesting to target: the AVX family.
+    let series: Vec<(String, Vec<Option<f64>>)> = rows
+        .iter()
+        .filter(|(name, _)| name.contains("avx"))
+        .take(MAX_CHART_SERIES)
+        .map(|(name, b)| {
+            (
+                name.trim_start_matches("x86_").to_string(),
+                b.weekly_share_of("yes"),
+            )
+        })
+        .collect();
+    out.push_str(&render_chart(
+        "Share of x86 machines with AVX features over time",
+        range,
+        &series,
+    ));
+
+    Ok(out)
+}
+
+/// Render a section for each cache level and kind which some machines have.
+fn render_caches(range: &Range, min_users: f64, caches: Vec<(&str, Breakdown)>) -> String {
+    let mut out = String::new();
+    for (cache, breakdown) in caches {
+        // Most machines have either split or unified caches at each level, not both.  Skip the kinds nobody has.
+        if breakdown.total.iter().all(|x| x.0 == "0 B") {
+            continue;
+        }
+
+        out.push_str(&render_distribution(
+            &format!("{} cache", cache.to_uppercase()),
+            "Size",
+            range,
+            min_users,
+            breakdown,
+        ));
+    }
+    out
+}
+
+/// Render the body of a page, or `None` if it doesn't have enough data to publish.
+async fn render_page_body(
+    client: &Client,
+    application: Option<Uuid>,
+    range: &Range,
+    min_users: f64,
+) -> Result<Option<(f64, String)>> {
+    // Every submission writes the country table, so it's the best estimate of the total.
+    let countries = fetch_breakdown(
+        client,
+        "cf_country",
+        &["country"],
+        &[],
+        application,
+        range,
+        label_text("country"),
+    )
+    .await?;
+    let machines = countries.total_machines();
+    if machines < min_users {
+        return Ok(None);
+    }
+
+    let mut body = String::new();
+    write!(
+        body,
+        "<p>About {:.0} machines reported between {} and {}.</p>",
+        machines,
+        range.from.format("%Y-%m-%d"),
+        (range.to - CDuration::days(1)).format("%Y-%m-%d")
+    )
+    .unwrap();
+
+    body.push_str(&render_simd_section(client, application, range, min_users).await?);
+
+    body.push_str(
+        "<h2>CPU caches</h2><p>Sizes are rounded down to bands before they are stored.</p>",
+    );
+    let mut caches = vec![];
+    for cache in CACHE_COLUMNS {
+        let breakdown = fetch_breakdown(
+            client,
+            "cpu_caches",
+            &[*cache],
+            &[],
+            application,
+            range,
+            label_bytes(cache),
+        )
+        .await?;
+        caches.push((*cache, breakdown));
+    }
+    body.push_str(&render_caches(range, min_users, caches));
+
+    body.push_str("<h2>Memory</h2>");
+    let memory = fetch_breakdown(
+        client,
+        "memory",
+        &["total_memory"],
+        &[],
+        application,
+        range,
+        label_bytes("total_memory"),
+    )
+    .await?;
+    body.push_str(&render_distribution(
+        "Total memory",
+        "At least",
+        range,
+        min_users,
+        memory,
+    ));
+
+    body.push_str("<h2>Countries</h2><p>XX means the country is unknown.</p>");
+    body.push_str(&render_distribution(
+        "Country", "Country", range, min_users, countries,
+    ));
+
+    Ok(Some((machines, body)))
+}
+
+fn wrap_page(title: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+<style>{STYLE}</style>
+</head>
+<body>
+<main>
+<h1>{title}</h1>
+{body}
+</main>
+</body>
+</html>
+"#,
+        title = escape(title),
+    )
+}
+
+pub async fn render_site(client: &Client, options: &SiteOptions) -> Result<()> {
+    // Partial weeks would make the end of every chart look like a drop, so stop at the start of this one.
+    let today = Utc::now().duration_trunc(CDuration::days(1))?;
+    let to = today - CDuration::days(today.weekday().num_days_from_monday() as i64);
+    let from = to - CDuration::weeks(options.weeks);
+    let range = Range {
+        from,
+        to,
+        weeks: (0..options.weeks)
+            .map(|i| from + CDuration::weeks(i))
+            .collect(),
+    };
+
+    tokio::fs::create_dir_all(&options.output).await?;
+
+    // Note that application ids are the tokens clients send.  They must never end up in the output.
+    let mut pages: Vec<(String, String, Option<Uuid>)> =
+        vec![("All applications".to_string(), "all".to_string(), None)];
+    let rows = client
+        .query("SELECT id, name FROM application ORDER BY name", &[])
+        .await?;
+    for r in rows {
+        let name: String = r.get("name");
+        let mut slug = format!("app-{}", slugify(&name));
+        while pages.iter().any(|p| p.1 == slug) {
+            slug.push('_');
+        }
+        pages.push((name, slug, Some(r.get("id"))));
+    }
+
+    let mut index = String::from("<ul>");
+    for (name, slug, application) in pages.iter() {
+        let (machines, body) =
+            match render_page_body(client, *application, &range, options.min_users).await? {
+                Some(x) => x,
+                None => {
+                    log::info!("Skipping {} because it doesn't have enough data", name);
+                    continue;
+                }
+            };
+
+        let path = options.output.join(format!("{}.html", slug));
+        tokio::fs::write(&path, wrap_page(name, &body)).await?;
+        log::info!("Wrote {}", path.display());
+
+        write!(
+            index,
+            r#"<li><a href="{}.html">{}</a>: about {:.0} machines</li>"#,
+            slug,
+            escape(name),
+            machines
+        )
+        .unwrap();
+    }
+    index.push_str("</ul>");
+
+    write!(
+        index,
+        "<p>Covers {} through {}. Generated {}.</p>",
+        range.from.format("%Y-%m-%d"),
+        (range.to - CDuration::days(1)).format("%Y-%m-%d"),
+        Utc::now().format("%Y-%m-%d %H:%M UTC")
+    )
+    .unwrap();
+
+    let path = options.output.join("index.html");
+    tokio::fs::write(&path, wrap_page("Hardware survey results", &index)).await?;
+    log::info!("Wrote {}", path.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn range(weeks: i64) -> Range {
+        let from = Utc.with_ymd_and_hms(2022, 6, 6, 0, 0, 0).unwrap();
+        Range {
+            from,
+            to: from + CDuration::weeks(weeks),
+            weeks: (0..weeks).map(|i| from + CDuration::weeks(i)).collect(),
+        }
+    }
+
+    /// A breakdown where each category has the same count every week, and no machine is in two categories.
+    fn breakdown(weeks: usize, counts: &[(&str, f64)]) -> Breakdown {
+        let machines = counts.iter().map(|x| x.1).sum();
+        Breakdown {
+            total: counts.iter().map(|(c, n)| (c.to_string(), *n)).collect(),
+            by_week: counts
+                .iter()
+                .map(|(c, n)| (c.to_string(), vec![*n; weeks]))
+                .collect(),
+            machines,
+            machines_by_week: vec![machines; weeks],
+        }
+    }
+
+    fn headings(html: &str) -> Vec<&str> {
+        html.split("<h3>")
+            .skip(1)
+            .map(|x| x.split("</h3>").next().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_cache_sections_are_the_cache_levels() {
+        // Every cache column is shown, and nothing else in the table is.
+        let table = crate::reports::find_table("cpu_caches").unwrap();
+        assert!(table.columns_named(CACHE_COLUMNS).is_some());
+        let others: Vec<&str> = table
+            .columns
+            .iter()
+            .map(|c| c.name)
+            .filter(|c| !CACHE_COLUMNS.contains(c))
+            .collect();
+        assert_eq!(others, vec!["bins_version"]);
+
+        let range = range(4);
+        let caches = CACHE_COLUMNS
+            .iter()
+            .map(|c| (*c, breakdown(4, &[("32 KiB", 50.0), ("64 KiB", 50.0)])))
+            .collect();
+        assert_eq!(
+            headings(&render_caches(&range, 10.0, caches)),
+            vec![
+                "L1I cache",
+                "L1D cache",
+                "L1U cache",
+                "L2I cache",
+                "L2D cache",
+                "L2U cache",
+                "L3I cache",
+                "L3D cache",
+                "L3U cache"
+            ]
+        );
+
+        // Kinds of cache nobody has are left out.
+        let caches = vec![
+            ("l1i", breakdown(4, &[("32 KiB", 50.0)])),
+            ("l1u", breakdown(4, &[("0 B", 50.0)])),
+        ];
+        assert_eq!(
+            headings(&render_caches(&range, 10.0, caches)),
+            vec!["L1I cache"]
+        );
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            escape(r#"<a href="x">Tom & Jerry's</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+        assert_eq!(escape("plain"), "plain");
+    }
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(slugify("Synthizer 2.0!"), "synthizer-2-0");
+        assert_eq!(slugify("--My  Game--"), "my-game");
+        assert_eq!(slugify("Ünïcode app"), "n-code-app");
+        assert_eq!(slugify(" ?! "), "application");
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(32 << 10), "32 KiB");
+        assert_eq!(format_bytes(8 << 30), "8 GiB");
+        assert_eq!(format_bytes(1 << 50), "1024 TiB");
+    }
+
+    #[test]
+    fn test_fold_rare() {
+        let mut b = breakdown(2, &[("a", 100.0), ("b", 5.0), ("c", 3.0)]);
+        b.fold_rare(10.0);
+        assert_eq!(
+            b.total,
+            vec![("a".to_string(), 100.0), ("other".to_string(), 8.0)]
+        );
+        assert_eq!(b.by_week["other"], vec![8.0, 8.0]);
+        assert!(!b.by_week.contains_key("b"));
+        assert!(!b.by_week.contains_key("c"));
+        // Folding doesn't change how many machines there are.
+        assert_eq!(b.total_machines(), 108.0);
+
+        let mut b = breakdown(2, &[("a", 100.0)]);
+        b.fold_rare(10.0);
+        assert_eq!(b.total, vec![("a".to_string(), 100.0)]);
+        assert!(!b.by_week.contains_key("other"));
+    }
+
+    #[test]
+    fn test_shares_use_machines_not_sums() {
+        // Every machine upgraded partway through, so they're in both categories.
+        let mut b = breakdown(2, &[("8 GiB", 100.0), ("16 GiB", 100.0)]);
+        b.machines = 100.0;
+        b.machines_by_week = vec![100.0, 0.0];
+        assert_eq!(b.share_of("8 GiB"), 1.0);
+        assert_eq!(b.weekly_share_of("16 GiB"), vec![Some(1.0), None]);
+    }
+
+    #[test]
+    fn test_min_users_suppression() {
+        let range = range(2);
+
+        let html = render_distribution(
+            "Total memory",
+            "At least",
+            &range,
+            10.0,
+            breakdown(2, &[("8 GiB", 4.0), ("16 GiB", 5.0)]),
+        );
+        assert!(html.contains("Not enough data yet."));
+        assert!(!html.contains("GiB"));
+
+        let html = render_distribution(
+            "Total memory",
+            "At least",
+            &range,
+            10.0,
+            breakdown(2, &[("8 GiB", 50.0), ("16 GiB", 40.0), ("3 TiB", 2.0)]),
+        );
+        assert!(html.contains("8 GiB"));
+        assert!(html.contains("16 GiB"));
+        assert!(html.contains("other"));
+        assert!(!html.contains("3 TiB"));
+    }
+}
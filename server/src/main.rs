@@ -1,23 +1,94 @@
 mod anonymization;
 mod api;
+mod client_ip;
+mod config;
+mod db;
+mod geoip;
+mod html_report;
+mod metrics;
+mod migrations;
+mod reports;
+mod rollups;
+mod uuid_cache;
 mod writer;
 
-use std::str::FromStr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::time::Duration;
 
 use anyhow::Result;
 use clap::Parser;
 use warp::Filter;
 
+/// How many admin requests can use the database at once.
+const ADMIN_CONNECTIONS: usize = 2;
+
+/// How many JSON reports can run at once.  Each holds a database connection while it runs.
+const REPORT_CONNECTIONS: usize = 2;
+
 #[derive(Parser)]
 struct Args {
-    #[clap(default_value_t=String::from("127.0.0.1"))]
+    /// TOML file to read settings from.  See config.example.toml.
+    #[clap(long = "--config")]
+    config: Option<PathBuf>,
+
+    /// Listen on this address instead of the ones in the config.
     #[clap(long = "--address")]
-    address: String,
+    address: Option<IpAddr>,
 
-    #[clap(default_value_t = 10000)]
+    /// Listen on this port instead of the ones in the config.
     #[clap(long = "--port")]
-    port: u16,
+    port: Option<u16>,
+
+    /// Apply database migrations and exit.
+    #[clap(long = "--migrate-only")]
+    migrate_only: bool,
+
+    /// Don't apply database migrations at startup.  Instead, refuse to start if the database doesn't have all of them.
+    #[clap(long = "--no-migrate", conflicts_with = "migrate-only")]
+    no_migrate: bool,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Render the reports as a static HTML site and exit, instead of serving.
+    RenderReports {
+        /// Directory to write the site to.  Created if it doesn't exist.
+        #[clap(long = "--output")]
+        output: PathBuf,
+
+        /// How many whole weeks, ending with last week, to report on.
+        #[clap(default_value_t = 26)]
+        #[clap(long = "--weeks")]
+        weeks: i64,
+
+        /// Leave out values, sections, and applications seen on fewer than this many machines.
+        #[clap(default_value_t = 10.0)]
+        #[clap(long = "--min-users")]
+        min_users: f64,
+    },
+}
+
+/// Resolves on ctrl+c or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Could not listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate => {},
+    }
+    log::info!("Shutting down");
 }
 
 #[tokio::main(flavor = "multi_thread")]
@@ -26,55 +97,184 @@ async fn main() -> Result<()> {
 
     let args = Args::parse();
 
-    let ip: std::net::IpAddr =
-        std::net::IpAddr::from_str(&args.address).expect("Could not parse IP address");
+    let mut config = config::Config::load(args.config.as_deref(), std::env::vars())?;
 
-    let dburl = std::env::var("DATABASE_URL").expect("DATABASE_URL env var must be set");
-    let writer = writer::spawn(&dburl).await?;
+    // For compatibility, these replace the configured addresses with one.  Whichever isn't given comes from the first
+    // configured address.
+    if args.address.is_some() || args.port.is_some() {
+        let first = config.server.listen[0];
+        config.server.listen = vec![SocketAddr::new(
+            args.address.unwrap_or_else(|| first.ip()),
+            args.port.unwrap_or_else(|| first.port()),
+        )];
+    }
 
-    let cf_ip = warp::header("CF-Connecting-IP").map(|x: String| Some(x));
-    let remote_ip = warp::filters::addr::remote().map(|x: Option<std::net::SocketAddr>| {
-        // This half of the filter only matches if the cloudflare header wasn't present, so log here.
-        //
-        // Also, if we got here and didn't get the ip at all, something is weird about our deployment, so log that too.
-        only_every::only_every!(
-            Duration::from_secs(30),
-            log::warn!("Unable to get IP from cloudflare. Falling back to remote addr")
-        );
-        if x.is_none() {
-            only_every::only_every!(
-                Duration::from_secs(30),
-                log::error!("Warp is unable to extract the remote address")
-            );
+    let dburl = config.database_url().to_string();
+
+    if let Some(Command::RenderReports {
+        output,
+        weeks,
+        min_users,
+    }) = args.command
+    {
+        let client = db::connect(&dburl, "rendering reports").await?;
+        return html_report::render_site(
+            &client,
+            &html_report::SiteOptions {
+                output,
+                weeks,
+                min_users,
+            },
+        )
+        .await;
+    }
+
+    {
+        let mut client = db::connect(&dburl, "migrations").await?;
+        if args.no_migrate {
+            migrations::verify(&mut client).await?;
+        } else {
+            migrations::run(&mut client).await?;
         }
-        x.map(|y| y.ip().to_string())
-    });
-    let ip_filter = cf_ip.or(remote_ip).unify();
+    }
+    if args.migrate_only {
+        return Ok(());
+    }
 
-    // Extract a country code as a 2-character array.
-    let country_filter =
-        warp::filters::header::optional::<String>("CF-IPCountry").map(|x: Option<String>| {
-            if x.is_none() {
+    let writer = writer::spawn(
+        &dburl,
+        writer::WriterOptions {
+            uuid_cache_refresh: Duration::from_secs(config.writer.uuid_cache_refresh_secs),
+            dimension_allowlist: config.dimension_allowlist()?,
+            workers: config.writer.workers,
+            queue_size: config.writer.queue_size,
+            unknown_ip: config.writer.unknown_ip.clone(),
+            unknown_country: config.writer.unknown_country.clone(),
+            bins: config.anonymization.clone(),
+            geoip: config
+                .geoip
+                .database
+                .as_deref()
+                .map(geoip::GeoIp::open)
+                .transpose()?,
+        },
+    )
+    .await?;
+
+    tokio::spawn(rollups::maintain_task(
+        dburl.clone(),
+        Duration::from_secs(config.reports.rollup_interval_secs),
+    ));
+
+    if config.admin.token.is_none() {
+        log::warn!(
+            "admin.token is not set. The admin API and JSON reports will reject all requests"
+        );
+    }
+    let admin = api::admin::routes(std::sync::Arc::new(api::admin::AdminContext {
+        writer: writer.clone(),
+        pool: db::pool(&dburl, ADMIN_CONNECTIONS)?,
+        token: config.admin.token.clone(),
+    }));
+    let health = api::health::routes(writer.clone());
+    let metrics = api::metrics::routes(writer.clone());
+    let reports = api::reports::routes(
+        db::pool(&dburl, REPORT_CONNECTIONS)?,
+        config.admin.token.clone(),
+    );
+
+    // Warp wants header names to live forever.  This is only made once, so leaking it is fine.
+    let country_header: &'static str =
+        Box::leak(config.proxy.country_header.clone().into_boxed_str());
+
+    if config.proxy.trusted_proxies.is_empty() {
+        log::warn!(
+            "proxy.trusted_proxies is not set, so the IP and country headers are ignored and clients' IPs are the \
+             addresses they connected from"
+        );
+    }
+    let ip_resolver = std::sync::Arc::new(client_ip::ClientIpResolver::new(
+        config.proxy.ip_header.clone(),
+        config.proxy.trusted_proxies.clone(),
+    ));
+    let country_resolver = ip_resolver.clone();
+    let ip_filter = warp::filters::addr::remote()
+        .and(warp::header::headers_cloned())
+        .map(move |peer, headers| {
+            let ip = ip_resolver.resolve(peer, &headers);
+            // Only happens if we aren't listening on TCP, which means something is weird about our deployment.
+            if ip.is_none() {
                 only_every::only_every!(
                     Duration::from_secs(30),
-                    log::warn!("Missing CF-IPCountry header")
+                    log::error!("Warp is unable to extract the remote address")
+                );
+            }
+            ip.map(|x| x.to_string())
+        });
+
+    // Extract a country code as a 2-character array, if it came from one of our proxies.  Without the header, the
+    // writer falls back to GeoIP if it can, so only complain if it can't.
+    let have_geoip = config.geoip.database.is_some();
+    let country_filter = warp::filters::addr::remote()
+        .and(warp::filters::header::optional::<String>(country_header))
+        .map(move |peer, x: Option<String>| {
+            // Untrusted peers' IP headers are already warned about when resolving the IP.
+            if !country_resolver.is_trusted_peer(peer) {
+                return None;
+            }
+            if x.is_none() && !have_geoip {
+                only_every::only_every!(
+                    Duration::from_secs(30),
+                    log::warn!("Missing {} header", country_header)
                 );
             }
             x
         });
 
-    let submit = warp::path!("submit" / "v1")
-        .and(warp::post())
-        .and(warp::filters::body::content_length_limit(1024 * 10))
-        .and(warp::query::<api::submit_v1::Qparams>())
+    // Everything a submission route needs besides its payload's version.
+    let submission = warp::post()
+        .and(warp::filters::body::content_length_limit(
+            config.server.body_limit,
+        ))
+        .and(warp::query::<api::submit::Qparams>())
         .and(ip_filter)
         .and(country_filter)
-        .and(warp::filters::body::bytes())
-        .then(move |qparams, ip, country, body| {
-            api::submit_v1::submit_v1(writer.clone(), qparams, ip, country, body)
-        })
+        .and(warp::filters::body::bytes());
+
+    let submit_writer = writer.clone();
+    let submit_v1 = warp::path!("submit" / "v1").and(submission.clone()).then(
+        move |qparams, ip, country, body| {
+            api::submit_v1::submit_v1(submit_writer.clone(), qparams, ip, country, body)
+        },
+    );
+    let submit_writer = writer.clone();
+    let submit_v2 =
+        warp::path!("submit" / "v2")
+            .and(submission)
+            .then(move |qparams, ip, country, body| {
+                api::submit_v2::submit_v2(submit_writer.clone(), qparams, ip, country, body)
+            });
+
+    let routes = submit_v1
+        .or(submit_v2)
+        .or(health)
+        .or(admin)
+        .or(reports)
+        .or(metrics)
         .with(warp::log("hwsurvey_server::routing"));
 
-    warp::serve(submit).run((ip, args.port)).await;
+    // This stops accepting connections and waits for requests in progress, so nothing is added to the queue after.
+    let shutdown = futures_util::FutureExt::shared(shutdown_signal());
+    let servers = config.server.listen.iter().map(|addr| {
+        let (bound, server) =
+            warp::serve(routes.clone()).bind_with_graceful_shutdown(*addr, shutdown.clone());
+        log::info!("Listening on {}", bound);
+        server
+    });
+    futures_util::future::join_all(servers).await;
+
+    writer
+        .shutdown(Duration::from_secs(config.writer.shutdown_timeout_secs))
+        .await;
     Ok(())
 }
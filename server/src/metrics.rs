@@ -0,0 +1,112 @@
+//! Prometheus metrics, served at `/metrics`.
+//!
+//! Everything is registered with the default registry the first time it's touched.
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    HistogramVec, IntCounter, IntCounterVec, IntGauge,
+};
+
+pub static SUBMISSIONS_ACCEPTED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "hwsurvey_submissions_accepted_total",
+        "Submissions added to the writer's queue"
+    )
+    .unwrap()
+});
+
+pub static SUBMISSIONS_REJECTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "hwsurvey_submissions_rejected_total",
+        "Submissions which were turned away, by reason",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+pub const REJECTED_BAD_JSON: &str = "bad_json";
+pub const REJECTED_UNKNOWN_TOKEN: &str = "unknown_token";
+pub const REJECTED_QUEUE_FULL: &str = "queue_full";
+pub const REJECTED_BAD_COUNTRY: &str = "bad_country";
+
+/// Taken off the queue by a writer worker.
+pub static ITEMS_TAKEN: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "hwsurvey_writer_items_taken_total",
+        "Items taken off the queue by a writer"
+    )
+    .unwrap()
+});
+
+pub static ITEMS_WRITTEN: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "hwsurvey_writer_items_written_total",
+        "Items written to the database"
+    )
+    .unwrap()
+});
+
+/// Invalid, or part of a batch the database refused.
+pub static ITEMS_FAILED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "hwsurvey_writer_items_failed_total",
+        "Items which could not be written"
+    )
+    .unwrap()
+});
+
+/// Only updated when scraped.
+pub static QUEUE_DEPTH: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "hwsurvey_writer_queue_depth",
+        "Items waiting to be taken by a writer"
+    )
+    .unwrap()
+});
+
+pub static WRITE_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "hwsurvey_write_latency_seconds",
+        "Time taken by one upsert into a metrics table",
+        &["table"]
+    )
+    .unwrap()
+});
+
+pub static DB_RECONNECTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "hwsurvey_writer_reconnects_total",
+        "Times a writer got a new database connection after losing one"
+    )
+    .unwrap()
+});
+
+/// Register everything, so that metrics which haven't happened yet show up as 0 rather than missing.
+pub fn init() {
+    Lazy::force(&SUBMISSIONS_ACCEPTED);
+    for reason in [
+        REJECTED_BAD_JSON,
+        REJECTED_UNKNOWN_TOKEN,
+        REJECTED_QUEUE_FULL,
+        REJECTED_BAD_COUNTRY,
+    ] {
+        SUBMISSIONS_REJECTED.with_label_values(&[reason]);
+    }
+    Lazy::force(&ITEMS_TAKEN);
+    Lazy::force(&ITEMS_WRITTEN);
+    Lazy::force(&ITEMS_FAILED);
+    Lazy::force(&QUEUE_DEPTH);
+    Lazy::force(&WRITE_LATENCY);
+    Lazy::force(&DB_RECONNECTS);
+}
+
+/// Render everything in the default registry in the Prometheus text format.
+pub fn render() -> String {
+    use prometheus::Encoder;
+
+    let mut out = vec![];
+    prometheus::TextEncoder::new()
+        .encode(&prometheus::gather(), &mut out)
+        .expect("Encoding metrics to a Vec can't fail");
+    String::from_utf8(out).expect("The text format is UTF-8")
+}
@@ -0,0 +1,54 @@
+//! The database schema from `server/migrations`, embedded in the binary.
+//!
+//! By default we apply whatever the database doesn't have yet at startup.  With `--no-migrate` we instead refuse to
+//! start if it's behind, for deployments where something else owns the schema.
+use anyhow::{Context, Result};
+use tokio_postgres::Client;
+
+mod embedded {
+    refinery::embed_migrations!("migrations");
+}
+
+/// Apply any migrations the database doesn't have yet.
+pub async fn run(client: &mut Client) -> Result<()> {
+    let report = embedded::migrations::runner().run_async(client).await?;
+    if report.applied_migrations().is_empty() {
+        log::info!("Database schema is up to date");
+    }
+    for m in report.applied_migrations() {
+        log::info!("Applied migration {}", m);
+    }
+    Ok(())
+}
+
+/// Fail unless every migration we know about has been applied.
+pub async fn verify(client: &mut Client) -> Result<()> {
+    let runner = embedded::migrations::runner();
+    let expected = runner
+        .get_migrations()
+        .iter()
+        .map(|m| m.version())
+        .max()
+        .unwrap_or(0);
+    let applied = runner
+        .get_last_applied_migration_async(client)
+        .await
+        .context("Unable to read the schema history.  Has the database been migrated?")?
+        .map_or(0, |m| m.version());
+
+    if applied < expected {
+        anyhow::bail!(
+            "The database schema is at version {} but this server needs {}. Migrate it with --migrate-only first",
+            applied,
+            expected
+        );
+    }
+    if applied > expected {
+        log::warn!(
+            "The database schema is at version {}, which is newer than this server's {}",
+            applied,
+            expected
+        );
+    }
+    Ok(())
+}
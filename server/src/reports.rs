@@ -0,0 +1,430 @@
+//! Pre-defined reports over the metrics tables.
+//!
+//! Each report groups one table by its factors and estimates the number of distinct machines and IPs in each group by
+//! unioning the hlls.  Because of the union, a machine seen on several days of a period is only counted once.  Weekly
+//! and monthly reports read from the rollups maintained by [crate::rollups] rather than unioning every day each time.
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Client, Row};
+use uuid::Uuid;
+
+use crate::rollups::Rollup;
+
+#[derive(Copy, Clone, Debug)]
+pub enum ColumnKind {
+    Text,
+    Bool,
+    BigInt,
+}
+
+/// A column of a report.  `expr` is evaluated against the metrics table, which is aliased to `t`.
+#[derive(Debug)]
+pub struct Column {
+    pub name: &'static str,
+    pub expr: &'static str,
+    pub kind: ColumnKind,
+}
+
+/// A table we know how to report on.
+#[derive(Debug)]
+pub struct ReportTable {
+    pub name: &'static str,
+
+    /// Joins to resolve uuids of dimension tables to names.
+    pub joins: &'static str,
+
+    pub columns: &'static [Column],
+}
+
+const fn col(name: &'static str, expr: &'static str, kind: ColumnKind) -> Column {
+    Column { name, expr, kind }
+}
+
+const fn flag(name: &'static str, expr: &'static str) -> Column {
+    col(name, expr, ColumnKind::Bool)
+}
+
+/// Which version of the anonymization bins a row's values were rounded with.
+const fn bins_version() -> Column {
+    col("bins_version", "t.bins_version::BIGINT", ColumnKind::BigInt)
+}
+
+const fn bytes(name: &'static str, expr: &'static str) -> Column {
+    col(name, expr, ColumnKind::BigInt)
+}
+
+pub const REPORT_TABLES: &[ReportTable] = &[
+    ReportTable {
+        name: "cpu_capabilities",
+        joins: "JOIN os ON os.id = t.os
+JOIN cpu_manufacturer m ON m.id = t.cpu_manufacturer
+JOIN cpu_architecture a ON a.id = t.architecture",
+        columns: &[
+            col("os", "os.name", ColumnKind::Text),
+            col("cpu_manufacturer", "m.name", ColumnKind::Text),
+            col("architecture", "a.name", ColumnKind::Text),
+            flag("x86_sse2", "t.x86_sse2"),
+            flag("x86_sse3", "t.x86_sse3"),
+            flag("x86_ssse3", "t.x86_ssse3"),
+            flag("x86_sse4_1", "t.x86_sse4_1"),
+            flag("x86_popcnt_insn", "t.x86_popcnt_insn"),
+            flag("x86_fma3", "t.x86_fma3"),
+            flag("x86_fma4", "t.x86_fma4"),
+            flag("x86_xop", "t.x86_xop"),
+            flag("x86_avx", "t.x86_avx"),
+            flag("x86_avx2", "t.x86_avx2"),
+            flag("x86_avx512f", "t.x86_avx512f"),
+            flag("x86_avx512bw", "t.x86_avx512bw"),
+            flag("x86_avx512dq", "t.x86_avx512dq"),
+            flag("x86_avx512vl", "t.x86_avx512vl"),
+        ],
+    },
+    ReportTable {
+        name: "cpu_capabilities_arm",
+        joins: "JOIN os ON os.id = t.os
+JOIN cpu_manufacturer m ON m.id = t.cpu_manufacturer
+JOIN cpu_architecture a ON a.id = t.architecture",
+        columns: &[
+            col("os", "os.name", ColumnKind::Text),
+            col("cpu_manufacturer", "m.name", ColumnKind::Text),
+            col("architecture", "a.name", ColumnKind::Text),
+            flag("neon", "t.neon"),
+            flag("asimd_dotprod", "t.asimd_dotprod"),
+            flag("fp16", "t.fp16"),
+            flag("sve", "t.sve"),
+            flag("sve2", "t.sve2"),
+            col(
+                "sve_vector_length",
+                "t.sve_vector_length",
+                ColumnKind::BigInt,
+            ),
+        ],
+    },
+    ReportTable {
+        name: "cpu_caches",
+        joins: "",
+        columns: &[
+            bins_version(),
+            bytes("l1i", "t.l1i"),
+            bytes("l1d", "t.l1d"),
+            bytes("l1u", "t.l1u"),
+            bytes("l2i", "t.l2i"),
+            bytes("l2d", "t.l2d"),
+            bytes("l2u", "t.l2u"),
+            bytes("l3i", "t.l3i"),
+            bytes("l3d", "t.l3d"),
+            bytes("l3u", "t.l3u"),
+        ],
+    },
+    ReportTable {
+        name: "memory",
+        joins: "",
+        columns: &[bins_version(), bytes("total_memory", "t.total_memory")],
+    },
+    ReportTable {
+        name: "cf_country",
+        joins: "",
+        columns: &[
+            col("source", "t.source", ColumnKind::Text),
+            col("country", "t.country", ColumnKind::Text),
+        ],
+    },
+];
+
+pub fn find_table(name: &str) -> Option<&'static ReportTable> {
+    REPORT_TABLES.iter().find(|t| t.name == name)
+}
+
+impl ReportTable {
+    /// Look up columns by name, returning `None` if any don't exist.
+    pub fn columns_named(&'static self, names: &[&str]) -> Option<Vec<&'static Column>> {
+        names
+            .iter()
+            .map(|n| self.columns.iter().find(|c| c.name == *n))
+            .collect()
+    }
+}
+
+/// How to split the requested range.
+#[derive(Copy, Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    /// One group for the whole range.
+    #[default]
+    Total,
+    Day,
+
+    /// Weeks start on Monday.
+    Week,
+    Month,
+}
+
+impl Granularity {
+    fn rollup(&self) -> Option<Rollup> {
+        match self {
+            Granularity::Total | Granularity::Day => None,
+            Granularity::Week => Some(Rollup::Weekly),
+            Granularity::Month => Some(Rollup::Monthly),
+        }
+    }
+}
+
+/// Restricts a report to some rows.
+#[derive(Debug)]
+pub enum Filter {
+    /// Rows where the column isn't null.
+    NotNull(&'static Column),
+
+    /// Rows where the column, as text, is this value.
+    Equals(&'static Column, String),
+}
+
+#[derive(Debug)]
+pub struct ReportQuery {
+    pub application: Option<Uuid>,
+
+    /// Columns to group by.  Machines are counted once per distinct combination of these, so grouping by fewer
+    /// columns gives correct counts for those columns rather than requiring sums over the others.
+    pub columns: Vec<&'static Column>,
+
+    pub filters: Vec<Filter>,
+
+    /// Inclusive.  For weeks and months, every period which overlaps the range is included in full.
+    pub from: DateTime<Utc>,
+
+    /// Exclusive.
+    pub to: DateTime<Utc>,
+
+    pub granularity: Granularity,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportRow {
+    /// Start of the period this row covers, or `None` if it covers the whole range.
+    pub period: Option<DateTime<Utc>>,
+
+    #[serde(flatten)]
+    pub values: serde_json::Map<String, serde_json::Value>,
+
+    pub users_by_id: Option<f64>,
+    pub users_by_ip: Option<f64>,
+}
+
+fn build_report_string(
+    table: &ReportTable,
+    columns: &[&Column],
+    filters: &[Filter],
+    granularity: Granularity,
+) -> String {
+    let period = match granularity {
+        Granularity::Total => "NULL::TIMESTAMP WITH TIME ZONE",
+        Granularity::Day => "t.day",
+        Granularity::Week | Granularity::Month => "t.period",
+    };
+
+    let (source, range_filter) = match granularity.rollup() {
+        None => (
+            table.name.to_string(),
+            "t.day >= $1 AND t.day < $2".to_string(),
+        ),
+        Some(r) => (
+            r.table_name(table.name),
+            format!(
+                "t.period < $2 AND t.period + interval '1 {}' > $1",
+                r.unit()
+            ),
+        ),
+    };
+
+    let select_cols = itertools::join(
+        columns
+            .iter()
+            .map(|c| format!(", {} AS {}", c.expr, c.name)),
+        "",
+    );
+
+    // Values to compare against are parameters, numbered from after the range and application in the order of the
+    // filters.  See [filter_params].
+    let mut next_param = 3;
+    let filter_clauses = itertools::join(
+        filters.iter().map(|f| match f {
+            Filter::NotNull(c) => format!(" AND ({}) IS NOT NULL", c.expr),
+            Filter::Equals(c, _) => {
+                next_param += 1;
+                format!(" AND ({})::TEXT = ${}", c.expr, next_param)
+            }
+        }),
+        "",
+    );
+
+    // Group by position, since the factors are the first columns after the period.
+    let group_cols = itertools::join(1..=columns.len() + 1, ", ");
+
+    format!(
+        r#"
+SELECT {period} AS period{select_cols},
+    hll_cardinality(hll_union_agg(t.users_by_id)) AS users_by_id,
+    hll_cardinality(hll_union_agg(t.users_by_ip)) AS users_by_ip
+FROM {source} t
+{joins}
+WHERE {range_filter} AND ($3::UUID IS NULL OR t.application = $3){filter_clauses}
+GROUP BY {group_cols}
+ORDER BY {group_cols}"#,
+        joins = table.joins,
+    )
+}
+
+/// The values the filters of a query compare against, in the order [build_report_string] numbers them.
+fn filter_params(filters: &[Filter]) -> impl Iterator<Item = &(dyn ToSql + Sync)> {
+    filters.iter().filter_map(|f| match f {
+        Filter::NotNull(_) => None,
+        Filter::Equals(_, v) => Some(v as &(dyn ToSql + Sync)),
+    })
+}
+
+fn convert_row(columns: &[&Column], row: &Row) -> ReportRow {
+    use serde_json::Value;
+
+    let mut values = serde_json::Map::new();
+    for c in columns {
+        let v = match c.kind {
+            ColumnKind::Text => row
+                .get::<_, Option<String>>(c.name)
+                .map_or(Value::Null, Value::from),
+            ColumnKind::Bool => row
+                .get::<_, Option<bool>>(c.name)
+                .map_or(Value::Null, Value::from),
+            ColumnKind::BigInt => row
+                .get::<_, Option<i64>>(c.name)
+                .map_or(Value::Null, Value::from),
+        };
+        values.insert(c.name.to_string(), v);
+    }
+
+    ReportRow {
+        period: row.get("period"),
+        values,
+        users_by_id: row.get("users_by_id"),
+        users_by_ip: row.get("users_by_ip"),
+    }
+}
+
+pub async fn run_report(
+    client: &Client,
+    table: &ReportTable,
+    query: &ReportQuery,
+) -> Result<Vec<ReportRow>> {
+    let qstring = build_report_string(table, &query.columns, &query.filters, query.granularity);
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&query.from, &query.to, &query.application];
+    params.extend(filter_params(&query.filters));
+    let rows = client.query(&qstring, &params[..]).await?;
+    Ok(rows
+        .iter()
+        .map(|r| convert_row(&query.columns, r))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory() -> &'static ReportTable {
+        find_table("memory").unwrap()
+    }
+
+    #[test]
+    fn test_find_table() {
+        for t in REPORT_TABLES {
+            assert_eq!(find_table(t.name).unwrap().name, t.name);
+        }
+        assert!(find_table("application").is_none());
+        assert!(find_table("memory; DROP TABLE memory").is_none());
+        assert!(find_table("").is_none());
+    }
+
+    #[test]
+    fn test_columns_named() {
+        let cols = memory().columns_named(&["total_memory"]).unwrap();
+        assert_eq!(cols.len(), 1);
+        assert_eq!(cols[0].expr, "t.total_memory");
+        assert!(memory().columns_named(&[]).unwrap().is_empty());
+        assert!(memory().columns_named(&["total_memory", "l1i"]).is_none());
+        assert!(memory().columns_named(&["t.total_memory"]).is_none());
+    }
+
+    /// Column names are pasted into the query as aliases, so they have to be plain identifiers.
+    #[test]
+    fn test_column_names_are_identifiers() {
+        for t in REPORT_TABLES {
+            for (i, c) in t.columns.iter().enumerate() {
+                assert!(
+                    c.name
+                        .chars()
+                        .all(|ch| ch.is_ascii_lowercase() || ch.is_ascii_digit() || ch == '_'),
+                    "{}.{}",
+                    t.name,
+                    c.name
+                );
+                assert!(
+                    t.columns[..i].iter().all(|o| o.name != c.name),
+                    "{}.{} is duplicated",
+                    t.name,
+                    c.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_build_report_string() {
+        let table = find_table("cpu_capabilities").unwrap();
+        let cols = table.columns_named(&["os", "x86_avx"]).unwrap();
+        let q = build_report_string(table, &cols, &[], Granularity::Total);
+        assert!(q.contains(
+            "SELECT NULL::TIMESTAMP WITH TIME ZONE AS period, os.name AS os, t.x86_avx AS x86_avx,"
+        ));
+        assert!(q.contains("FROM cpu_capabilities t\nJOIN os ON os.id = t.os"));
+        assert!(q.contains(
+            "WHERE t.day >= $1 AND t.day < $2 AND ($3::UUID IS NULL OR t.application = $3)\n"
+        ));
+        assert!(q.contains("GROUP BY 1, 2, 3\nORDER BY 1, 2, 3"));
+
+        let q = build_report_string(table, &[], &[], Granularity::Day);
+        assert!(q.contains("SELECT t.day AS period,\n"));
+        assert!(q.contains("GROUP BY 1\n"));
+
+        let q = build_report_string(table, &cols, &[], Granularity::Week);
+        assert!(q.contains("SELECT t.period AS period,"));
+        assert!(q.contains("FROM cpu_capabilities_weekly t"));
+        assert!(q.contains("WHERE t.period < $2 AND t.period + interval '1 week' > $1"));
+
+        let q = build_report_string(table, &cols, &[], Granularity::Month);
+        assert!(q.contains("FROM cpu_capabilities_monthly t"));
+        assert!(q.contains("interval '1 month'"));
+    }
+
+    #[test]
+    fn test_filters() {
+        let table = find_table("cpu_capabilities").unwrap();
+        let cols = table
+            .columns_named(&["os", "architecture", "x86_avx"])
+            .unwrap();
+        let filters = vec![
+            Filter::Equals(cols[1], "x86".to_string()),
+            Filter::NotNull(cols[2]),
+            Filter::Equals(cols[0], "linux".to_string()),
+        ];
+        let q = build_report_string(table, &cols[2..], &filters, Granularity::Total);
+        assert!(q.contains(
+            "($3::UUID IS NULL OR t.application = $3) AND (a.name)::TEXT = $4 AND (t.x86_avx) IS NOT NULL AND (os.name)::TEXT = $5\n"
+        ));
+
+        // Only comparisons take parameters, and in the same order.
+        let params = filter_params(&filters)
+            .map(|p| format!("{:?}", p))
+            .collect::<Vec<_>>();
+        assert_eq!(params, vec!["\"x86\"", "\"linux\""]);
+    }
+}
@@ -0,0 +1,147 @@
+//! Maintains the weekly and monthly rollups of the metrics tables.
+//!
+//! Summing daily counts double-counts machines which report on more than one day, so the rollups store the union of
+//! the daily hlls for each period.  Rather than trying to update them as items are written, we periodically recompute
+//! the current and previous period of each from the daily tables.  The previous one is included because items received
+//! just before a period ends may not have been written until after.
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio_postgres::Client;
+
+/// A kind of rollup.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Rollup {
+    Weekly,
+    Monthly,
+}
+
+impl Rollup {
+    pub const ALL: [Rollup; 2] = [Rollup::Weekly, Rollup::Monthly];
+
+    /// The unit this rolls up to, as understood by Postgres's `date_trunc` and intervals.
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Rollup::Weekly => "week",
+            Rollup::Monthly => "month",
+        }
+    }
+
+    /// Name of the rollup of the given metrics table.
+    pub fn table_name(&self, base: &str) -> String {
+        match self {
+            Rollup::Weekly => format!("{}_weekly", base),
+            Rollup::Monthly => format!("{}_monthly", base),
+        }
+    }
+}
+
+/// Get the factor columns of a rollup table, which are everything but the period and the hlls.
+async fn factor_columns(client: &Client, rollup_table: &str) -> Result<Vec<String>> {
+    let rows = client
+        .query(
+            "SELECT column_name::TEXT FROM information_schema.columns
+            WHERE table_schema = current_schema() AND table_name = $1
+            AND column_name NOT IN ('period', 'users_by_id', 'users_by_ip')
+            ORDER BY ordinal_position",
+            &[&rollup_table],
+        )
+        .await?;
+
+    if rows.is_empty() {
+        anyhow::bail!("Rollup table {} doesn't exist", rollup_table);
+    }
+
+    Ok(rows.iter().map(|r| r.get(0)).collect())
+}
+
+async fn refresh_one(client: &mut Client, base: &str, rollup: Rollup) -> Result<()> {
+    let rollup_table = rollup.table_name(base);
+    let unit = rollup.unit();
+    let columns = factor_columns(client, &rollup_table).await?;
+    let group_cols = itertools::join(1..=columns.len() + 1, ", ");
+    let columns = itertools::join(columns, ", ");
+
+    let tx = client.transaction().await?;
+
+    // Without this, two servers refreshing at once could both insert the same period.  Readers aren't blocked.
+    tx.batch_execute(&format!("LOCK TABLE {rollup_table} IN EXCLUSIVE MODE"))
+        .await?;
+
+    // If the rollup is empty, this is the first run and we fill in all of history.
+    let since: Option<chrono::DateTime<chrono::Utc>> = tx
+        .query_one(
+            &format!(
+                "SELECT CASE WHEN EXISTS(SELECT 1 FROM {rollup_table})
+                THEN date_trunc('{unit}', now() AT TIME ZONE 'UTC') AT TIME ZONE 'UTC' - interval '1 {unit}'
+                END"
+            ),
+            &[],
+        )
+        .await?
+        .get(0);
+
+    tx.execute(
+        &format!(
+            "DELETE FROM {rollup_table} WHERE $1::TIMESTAMP WITH TIME ZONE IS NULL OR period >= $1"
+        ),
+        &[&since],
+    )
+    .await?;
+
+    let inserted = tx
+        .execute(
+            &format!(
+                r#"
+INSERT INTO {rollup_table}(period, {columns}, users_by_id, users_by_ip)
+SELECT date_trunc('{unit}', day AT TIME ZONE 'UTC') AT TIME ZONE 'UTC', {columns},
+    hll_union_agg(users_by_id), hll_union_agg(users_by_ip)
+FROM {base}
+WHERE $1::TIMESTAMP WITH TIME ZONE IS NULL OR day >= $1
+GROUP BY {group_cols}"#
+            ),
+            &[&since],
+        )
+        .await?;
+
+    tx.commit().await?;
+
+    log::debug!(
+        "Refreshed {} since {:?} with {} rows",
+        rollup_table,
+        since,
+        inserted
+    );
+    Ok(())
+}
+
+/// Refresh every rollup of every metrics table.
+pub async fn refresh_all(client: &mut Client) -> Result<()> {
+    for table in crate::reports::REPORT_TABLES {
+        for rollup in Rollup::ALL {
+            refresh_one(client, table.name, rollup).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Refresh the rollups every `interval`, forever.
+pub async fn maintain_task(db_url: String, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+
+        // This runs rarely enough that connecting every time is simpler than noticing the connection went away.
+        let res = async {
+            let mut client = crate::db::connect(&db_url, "rollups").await?;
+            refresh_all(&mut client).await
+        }
+        .await;
+
+        match res {
+            Ok(()) => log::info!("Refreshed rollups"),
+            Err(e) => log::error!("Unable to refresh rollups: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! Caches mapping the strings clients send to the uuids of our dimension tables.
+//!
+//! The cache is immutable once built.  Changes are made by building a new one and swapping it in, so anything which
+//! grabbed the old one keeps a consistent view until it's done.
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::Result;
+use futures_util::StreamExt;
+use tokio::select;
+use tokio_postgres::{AsyncMessage, Client};
+use uuid::Uuid;
+
+/// Channel the triggers from the migrations notify on when a dimension table changes.
+const NOTIFY_CHANNEL: &str = "hwsurvey_dimensions";
+
+/// How long to wait before trying to listen again if the connection for notifications fails.
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// The tables which hold the values of a dimension, e.g. the known OSes.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Dimension {
+    Os,
+    CpuManufacturer,
+    CpuArchitecture,
+}
+
+impl Dimension {
+    pub const ALL: [Dimension; 3] = [
+        Dimension::Os,
+        Dimension::CpuManufacturer,
+        Dimension::CpuArchitecture,
+    ];
+
+    pub fn table_name(&self) -> &'static str {
+        match self {
+            Dimension::Os => "os",
+            Dimension::CpuManufacturer => "cpu_manufacturer",
+            Dimension::CpuArchitecture => "cpu_architecture",
+        }
+    }
+
+    pub fn from_table_name(name: &str) -> Option<Dimension> {
+        Dimension::ALL
+            .iter()
+            .copied()
+            .find(|d| d.table_name() == name)
+    }
+}
+
+/// Contains caches mapping various strings to the uuids they go with from the db.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UuidCache {
+    tokens: HashSet<Uuid>,
+    os: HashMap<String, uuid::Uuid>,
+    cpu_manufacturer: HashMap<String, Uuid>,
+    architecture: HashMap<String, Uuid>,
+}
+
+impl UuidCache {
+    pub async fn load(client: &Client) -> Result<UuidCache> {
+        let mut cache = UuidCache {
+            tokens: Default::default(),
+            os: Default::default(),
+            cpu_manufacturer: Default::default(),
+            architecture: Default::default(),
+        };
+
+        for dim in Dimension::ALL {
+            let query = format!("SELECT id, name FROM {}", dim.table_name());
+            let rows = client.query(&query, &[]).await?;
+            let dest = cache.table_mut(dim);
+            for r in rows {
+                dest.insert(r.get("name"), r.get("id"));
+            }
+
+            if !dest.contains_key("unknown") {
+                anyhow::bail!("Table {} is missing the unknown value", dim.table_name());
+            }
+        }
+
+        let rows = client
+            .query("SELECT id FROM application WHERE revoked_at IS NULL", &[])
+            .await?;
+        for r in rows {
+            cache.tokens.insert(r.get("id"));
+        }
+
+        Ok(cache)
+    }
+
+    fn table(&self, dim: Dimension) -> &HashMap<String, Uuid> {
+        match dim {
+            Dimension::Os => &self.os,
+            Dimension::CpuManufacturer => &self.cpu_manufacturer,
+            Dimension::CpuArchitecture => &self.architecture,
+        }
+    }
+
+    fn table_mut(&mut self, dim: Dimension) -> &mut HashMap<String, Uuid> {
+        match dim {
+            Dimension::Os => &mut self.os,
+            Dimension::CpuManufacturer => &mut self.cpu_manufacturer,
+            Dimension::CpuArchitecture => &mut self.architecture,
+        }
+    }
+
+    /// Look up a value, returning `None` if the database doesn't know about it.
+    pub fn find(&self, dim: Dimension, name: &str) -> Option<Uuid> {
+        self.table(dim).get(name).copied()
+    }
+
+    /// Get the uuid of the unknown value for a dimension.
+    pub fn unknown(&self, dim: Dimension) -> Uuid {
+        *self
+            .table(dim)
+            .get("unknown")
+            .expect("unknown is always present")
+    }
+
+    pub fn has_application(&self, uuid: &Uuid) -> bool {
+        self.tokens.contains(uuid)
+    }
+}
+
+/// A [UuidCache] which can be swapped out from under its readers.
+#[derive(Debug)]
+pub struct SharedUuidCache {
+    current: RwLock<Arc<UuidCache>>,
+
+    /// Whether [refresh_task] is listening for changes.
+    listening: AtomicBool,
+}
+
+/// Clears [SharedUuidCache::listening] when the refresh task stops listening, including by panicking.
+struct ListeningGuard<'a>(&'a AtomicBool);
+
+impl Drop for ListeningGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+impl SharedUuidCache {
+    pub fn new(cache: UuidCache) -> SharedUuidCache {
+        SharedUuidCache {
+            current: RwLock::new(Arc::new(cache)),
+            listening: AtomicBool::new(false),
+        }
+    }
+
+    /// Get the current cache.  Later swaps don't affect the returned value.
+    pub fn get(&self) -> Arc<UuidCache> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Whether changes to the database are being picked up.  If not, the cache is still usable, but new applications
+    /// and approved dimension values aren't seen until [refresh_task] reconnects.
+    pub fn is_listening(&self) -> bool {
+        self.listening.load(Ordering::Relaxed)
+    }
+
+    /// Replace the cache, returning whether it changed.
+    pub fn replace(&self, cache: UuidCache) -> bool {
+        let mut guard = self.current.write().unwrap();
+        if **guard == cache {
+            return false;
+        }
+
+        *guard = Arc::new(cache);
+        true
+    }
+
+    /// Make a modified copy of the current cache and swap it in.
+    fn update(&self, mutator: impl FnOnce(&mut UuidCache)) {
+        let mut guard = self.current.write().unwrap();
+        let mut new = (**guard).clone();
+        mutator(&mut new);
+        *guard = Arc::new(new);
+    }
+
+    pub fn add_application(&self, uuid: Uuid) {
+        self.update(|c| {
+            c.tokens.insert(uuid);
+        });
+    }
+
+    pub fn remove_application(&self, uuid: &Uuid) {
+        self.update(|c| {
+            c.tokens.remove(uuid);
+        });
+    }
+
+    /// Reload from the database and swap the result in.
+    pub async fn reload(&self, client: &Client) -> Result<()> {
+        let loaded = UuidCache::load(client).await?;
+        let debug_str = format!("{:?}", loaded);
+        if self.replace(loaded) {
+            log::info!("Uuid cache reloaded: {}", debug_str);
+        } else {
+            log::debug!("Uuid cache reloaded with no changes");
+        }
+
+        Ok(())
+    }
+}
+
+async fn refresh_task_fallible(
+    cache: &SharedUuidCache,
+    db_url: &str,
+    interval: Duration,
+) -> Result<()> {
+    let (client, mut connection) = tokio_postgres::connect(db_url, tokio_postgres::NoTls).await?;
+
+    // We have to drive the connection ourselves to see notifications, so forward them over a channel.  When the
+    // connection dies the sender is dropped, which is how the loop below finds out.
+    let (notify_sender, mut notify_receiver) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut messages = futures_util::stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(msg) = messages.next().await {
+            match msg {
+                Ok(AsyncMessage::Notification(n)) => {
+                    if notify_sender.send(n.payload().to_string()).is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    log::error!("Uuid cache refresh connection failed: {:?}", e);
+                    return;
+                }
+            }
+        }
+    });
+
+    client
+        .batch_execute(&format!("LISTEN {}", NOTIFY_CHANNEL))
+        .await?;
+
+    // Something may have changed while we weren't listening.
+    cache.reload(&client).await?;
+    cache.listening.store(true, Ordering::Relaxed);
+    let _listening = ListeningGuard(&cache.listening);
+
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        select! {
+            _ = ticker.tick() => {
+                log::debug!("Refreshing uuid cache on interval");
+            },
+            n = notify_receiver.recv() => {
+                match n {
+                    Some(table) => log::info!("Refreshing uuid cache because {} changed", table),
+                    None => anyhow::bail!("Lost the connection used for uuid cache notifications"),
+                }
+            }
+        }
+
+        cache.reload(&client).await?;
+    }
+}
+
+/// Keep the cache up to date, reloading every `interval` and whenever the database notifies us that a dimension table
+/// changed.
+pub async fn refresh_task(cache: Arc<SharedUuidCache>, db_url: String, interval: Duration) {
+    loop {
+        if let Err(e) = refresh_task_fallible(&cache, &db_url, interval).await {
+            log::error!(
+                "Uuid cache refreshing failed. Retrying in {:?}: {:?}",
+                RECONNECT_DELAY,
+                e
+            );
+        }
+
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
@@ -1,135 +1,143 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
 
 use anyhow::Result;
 use async_channel::{bounded, Receiver, Sender};
+use bytes::BytesMut;
 use chrono::{DateTime, Duration as CDuration, DurationRound, Utc};
-use tokio::select;
+use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod};
+use regex::Regex;
+use tokio_postgres::types::{to_sql_checked, IsNull, ToSql, Type};
 use tokio_postgres::{Client, Statement};
 use uuid::Uuid;
 
-use hwsurvey_payloads::PayloadV1;
+use hwsurvey_payloads::cpu::ArmCapabilities;
+use hwsurvey_payloads::memory::Memory;
+use hwsurvey_payloads::simdsp_bridger::{CacheInfo, CpuCapabilities};
+use hwsurvey_payloads::PayloadV2;
+
+use crate::anonymization::{round_sve_vector_length, Bins, BinsConfig};
+use crate::geoip::GeoIp;
+use crate::metrics;
+use crate::uuid_cache::{Dimension, SharedUuidCache, UuidCache};
 
 const CPU_CAPABILITIES_TABLE: &str = "cpu_capabilities";
+const CPU_CAPABILITIES_ARM_TABLE: &str = "cpu_capabilities_arm";
 const CPU_CACHES_TABLE: &str = "cpu_caches";
 const MEMORY_TABLE: &str = "memory";
 const CF_COUNTRY_TABLE: &str = "cf_country";
+const PENDING_DIMENSION_TABLE: &str = "pending_dimension";
 
-/// String to use for unknown IPs.
-const UNKNOWN_IP: &str = "123.123.123.123";
+/// Values of the `source` column of `cf_country`.
+const COUNTRY_FROM_HEADER: &str = "cloudflare";
+const COUNTRY_FROM_GEOIP: &str = "geoip";
+const COUNTRY_UNKNOWN: &str = "unknown";
 
-/// Unknown country code.
-///
-/// matches Cloudflare's value from:
-/// https://developers.cloudflare.com/fundamentals/get-started/reference/http-request-headers/
-const UNKNOWN_COUNTRY: &str = "XX";
+/// Most items to write in one transaction.
+const MAX_BATCH_ITEMS: usize = 100;
 
-/// How many items should we allow to be pending before we start erroring?
-const MAX_OUTSTANDING_ITEMS: usize = 1000;
+/// How long to wait before the first attempt to reconnect the writer.  Doubles on every failure up to
+/// [MAX_RECONNECT_DELAY].
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
 
 /// A cache of statements.
 type StatementCache = Mutex<HashMap<&'static str, Arc<Statement>>>;
 
+/// Returned by [WriterThread::send] when too many items are waiting to be written, usually because the database is
+/// down or can't keep up.
+#[derive(Debug)]
+pub struct QueueFull;
+
+impl std::fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The writer's queue is full")
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
 #[derive(Debug)]
 pub struct WorkItem {
     pub country: Option<String>,
     pub ip: Option<String>,
-    pub payload: PayloadV1,
+    pub payload: PayloadV2,
     pub received_at: chrono::DateTime<chrono::Utc>,
     pub token: Uuid,
 }
 
-/// Groups a bunch of parameters all our writing functions need.
-struct Context {
+/// Groups a bunch of parameters all our functions for adding to batches need.
+struct Context<'a> {
     day: DateTime<Utc>,
+    ip: &'a str,
     application: uuid::Uuid,
     os: uuid::Uuid,
     architecture: uuid::Uuid,
     cpu_manufacturer: Uuid,
 }
 
-/// Contains caches mapping various strings to the uuids they go with from the db.  Loaded at startup.
-#[derive(Debug)]
-struct UuidCache {
-    tokens: HashSet<Uuid>,
-    os: HashMap<String, uuid::Uuid>,
-    cpu_manufacturer: HashMap<String, Uuid>,
-    architecture: HashMap<String, Uuid>,
-}
-
-impl UuidCache {
-    async fn load(client: &Client) -> Result<UuidCache> {
-        let mut os = Default::default();
-        let mut cpu_manufacturer = Default::default();
-        let mut architecture = Default::default();
-        let mut tokens: HashSet<Uuid> = Default::default();
-
-        let tables: &mut [(&str, &mut HashMap<String, Uuid>)] = &mut [
-            ("os", &mut os),
-            ("cpu_manufacturer", &mut cpu_manufacturer),
-            ("cpu_architecture", &mut architecture),
-        ];
-
-        for (name, dest) in tables.iter_mut() {
-            let query = format!("SELECT id, name FROM {}", name);
-            let rows = client.query(&query, &[]).await?;
-            for r in rows {
-                dest.insert(r.get("name"), r.get("id"));
-            }
-        }
+/// Knobs for the writer.
+pub struct WriterOptions {
+    /// How often to reload the uuid cache from the database.
+    pub uuid_cache_refresh: Duration,
 
-        let rows = client.query("SELECT id FROM application", &[]).await?;
-        for r in rows {
-            tokens.insert(r.get("id"));
-        }
+    /// If set, values of OS, CPU manufacturer, and CPU architecture which the database doesn't know about but which
+    /// entirely match this pattern are recorded in `pending_dimension` so that an admin can approve them.  They are
+    /// still written as unknown.
+    pub dimension_allowlist: Option<Regex>,
 
-        Ok(UuidCache {
-            tokens,
-            os,
-            cpu_manufacturer,
-            architecture,
-        })
-    }
+    /// Number of writer tasks, each with its own connection.
+    pub workers: usize,
 
-    /// Get the OS, returning the uuid for the unknown value if this OS is unknown to us.
-    fn get_os(&self, os: &str) -> Uuid {
-        self.os
-            .get(os)
-            .unwrap_or_else(|| self.os.get("unknown").expect("unknown is always present"))
-            .clone()
-    }
+    /// How many items should we allow to be pending before we start erroring?
+    pub queue_size: usize,
 
-    /// Get the CPU manufacturer, returning the uuid for the unknown value if it is unknown to us.
-    fn get_cpu_manufacturer(&self, manufacturer: &str) -> Uuid {
-        self.cpu_manufacturer
-            .get(manufacturer)
-            .unwrap_or_else(|| self.os.get("unknown").expect("unknown is always present"))
-            .clone()
-    }
+    /// Strings to use for unknown IPs and countries.
+    pub unknown_ip: String,
+    pub unknown_country: String,
 
-    fn get_architecture(&self, arch: &str) -> Uuid {
-        self.architecture
-            .get(arch)
-            .unwrap_or_else(|| self.os.get("unknown").expect("unknown is always present"))
-            .clone()
-    }
+    /// Bins to use instead of the latest versions in the database.
+    pub bins: BinsConfig,
 
-    fn has_application(&self, uuid: &Uuid) -> bool {
-        self.tokens.contains(uuid)
-    }
+    /// Where to look up the country of items whose country header was missing.
+    pub geoip: Option<GeoIp>,
 }
 
 pub struct WriterThread {
     receiver: Receiver<WorkItem>,
     sender: Sender<WorkItem>,
-    uuid_cache: UuidCache,
+    uuid_cache: Arc<SharedUuidCache>,
+    dimension_allowlist: Option<Regex>,
+    workers: Mutex<Vec<tokio::task::JoinHandle<()>>>,
+    workers_alive: AtomicUsize,
+    pool: Pool,
+    unknown_ip: String,
+    unknown_country: String,
+    bins: Bins,
+    geoip: Option<GeoIp>,
+}
+
+/// Decrements [WriterThread::workers_alive] when a worker exits, including by panicking.
+struct AliveGuard<'a>(&'a AtomicUsize);
+
+impl Drop for AliveGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 90% of the queue, but at least 1 so that tiny queues are ready while they're empty.
+fn ready_queue_limit(capacity: usize) -> usize {
+    (capacity * 9 / 10).max(1)
 }
 
 impl WriterThread {
-    pub fn send(&self, item: WorkItem) -> Result<()> {
-        if !self.uuid_cache.has_application(&item.token) {
+    pub fn send(&self, item: WorkItem) -> Result<(), QueueFull> {
+        if !self.uuid_cache.get().has_application(&item.token) {
             only_every::only_every!(
                 Duration::from_secs(30),
                 log::info!(
@@ -137,12 +145,257 @@ impl WriterThread {
                     &item.token
                 )
             );
+            metrics::SUBMISSIONS_REJECTED
+                .with_label_values(&[metrics::REJECTED_UNKNOWN_TOKEN])
+                .inc();
             return Ok(());
         }
 
-        self.sender.try_send(item)?;
+        // The channel is only closed when we're shutting down, and turning items away is right then too.
+        if self.sender.try_send(item).is_err() {
+            metrics::SUBMISSIONS_REJECTED
+                .with_label_values(&[metrics::REJECTED_QUEUE_FULL])
+                .inc();
+            return Err(QueueFull);
+        }
+
+        metrics::SUBMISSIONS_ACCEPTED.inc();
         Ok(())
     }
+
+    /// How many items are waiting to be written.
+    pub fn queue_len(&self) -> usize {
+        self.receiver.len()
+    }
+
+    /// At or above this many queued items, we report that we aren't ready so that load balancers can send submissions
+    /// elsewhere before we start turning them away.
+    pub fn ready_queue_limit(&self) -> usize {
+        ready_queue_limit(self.sender.capacity().expect("The queue is bounded"))
+    }
+
+    /// How many workers are still running.  They only stop when we shut down, unless something has gone very wrong.
+    pub fn workers_alive(&self) -> usize {
+        self.workers_alive.load(Ordering::Relaxed)
+    }
+
+    /// Whether the uuid cache is keeping up with changes to the database.  See [SharedUuidCache::is_listening].
+    pub fn uuid_cache_listening(&self) -> bool {
+        self.uuid_cache.is_listening()
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// Check that we can get a connection from the writers' pool and run a query on it.
+    pub async fn check_database(&self, timeout: Duration) -> Result<()> {
+        tokio::time::timeout(timeout, async {
+            let client = self.pool.get().await?;
+            client.simple_query("SELECT 1").await?;
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Start accepting items for a newly registered application.
+    pub fn add_application(&self, token: Uuid) {
+        self.uuid_cache.add_application(token);
+    }
+
+    /// Stop accepting items for a revoked application.
+    ///
+    /// Items for this application which were already queued are still written.
+    pub fn remove_application(&self, token: &Uuid) {
+        self.uuid_cache.remove_application(token);
+    }
+
+    /// Stop accepting items, then give the workers up to `deadline` to write everything already queued.
+    pub async fn shutdown(&self, deadline: Duration) {
+        self.sender.close();
+        log::info!(
+            "Writer shutting down with {} items queued",
+            self.receiver.len()
+        );
+
+        let written_before = metrics::ITEMS_WRITTEN.get();
+        let workers = std::mem::take(&mut *self.workers.lock().unwrap());
+        let timed_out = tokio::time::timeout(deadline, futures_util::future::join_all(workers))
+            .await
+            .is_err();
+
+        // If we timed out, workers may still be going.  Items are taken before they're written or failed, so reading
+        // taken last keeps it the largest.
+        // Items which have been taken but are neither written nor failed are in a batch which is being written, or
+        // waiting to be retried.
+        let written = metrics::ITEMS_WRITTEN.get();
+        let failed = metrics::ITEMS_FAILED.get();
+        let taken = metrics::ITEMS_TAKEN.get();
+        let flushed = written - written_before;
+        let dropped = self.receiver.len() as u64 + taken - written - failed;
+
+        if dropped == 0 {
+            log::info!("Writer flushed {} items before shutting down", flushed);
+        } else {
+            log::error!(
+                "Writer flushed {} items but dropped {}{}",
+                flushed,
+                dropped,
+                if timed_out {
+                    " because it ran out of time"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+}
+
+/// A value of one of the factor columns of a metrics table.
+///
+/// Batches are grouped by these, so unlike `dyn ToSql` they can be hashed and compared.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+enum Factor {
+    Time(DateTime<Utc>),
+    Uuid(Uuid),
+    Bool(bool),
+    Int(i32),
+    BigInt(i64),
+    Text(String),
+}
+
+impl From<DateTime<Utc>> for Factor {
+    fn from(x: DateTime<Utc>) -> Factor {
+        Factor::Time(x)
+    }
+}
+
+impl From<Uuid> for Factor {
+    fn from(x: Uuid) -> Factor {
+        Factor::Uuid(x)
+    }
+}
+
+impl From<bool> for Factor {
+    fn from(x: bool) -> Factor {
+        Factor::Bool(x)
+    }
+}
+
+impl From<i32> for Factor {
+    fn from(x: i32) -> Factor {
+        Factor::Int(x)
+    }
+}
+
+impl From<i64> for Factor {
+    fn from(x: i64) -> Factor {
+        Factor::BigInt(x)
+    }
+}
+
+impl From<&str> for Factor {
+    fn from(x: &str) -> Factor {
+        Factor::Text(x.to_string())
+    }
+}
+
+impl ToSql for Factor {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> std::result::Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        // Check against the type of the variant we actually have, so that a factor in the wrong column is an error
+        // rather than garbage.
+        match self {
+            Factor::Time(x) => x.to_sql_checked(ty, out),
+            Factor::Uuid(x) => x.to_sql_checked(ty, out),
+            Factor::Bool(x) => x.to_sql_checked(ty, out),
+            Factor::Int(x) => x.to_sql_checked(ty, out),
+            Factor::BigInt(x) => x.to_sql_checked(ty, out),
+            Factor::Text(x) => x.to_sql_checked(ty, out),
+        }
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <DateTime<Utc> as ToSql>::accepts(ty)
+            || <Uuid as ToSql>::accepts(ty)
+            || <bool as ToSql>::accepts(ty)
+            || <i32 as ToSql>::accepts(ty)
+            || <i64 as ToSql>::accepts(ty)
+            || <String as ToSql>::accepts(ty)
+    }
+
+    to_sql_checked!();
+}
+
+/// The machine ids and ips seen for one row of a metrics table.
+#[derive(Debug, Default)]
+struct Users {
+    ids: HashSet<String>,
+    ips: HashSet<String>,
+}
+
+/// One table's part of a [Batch].
+#[derive(Debug)]
+struct TableBatch {
+    /// Names of the factor columns, in the same order as the values in the keys of `rows`.
+    factor_names: Vec<&'static str>,
+    rows: BTreeMap<Vec<Factor>, Users>,
+}
+
+/// Work items which are written together.
+///
+/// Items are aggregated as they are added, so each row of each table is only upserted once per batch no matter how many
+/// items land in it.
+#[derive(Debug, Default)]
+struct Batch {
+    /// Ordered, as are the rows of each table, so that every worker upserts rows in the same order.  Otherwise two
+    /// transactions touching the same rows could deadlock.
+    tables: BTreeMap<&'static str, TableBatch>,
+
+    /// Each item's rows on their own, so that if the batch can't be written the items can be written one at a time
+    /// and only the bad ones lost.
+    items: Vec<Batch>,
+}
+
+impl Batch {
+    /// Add a user to a row of a table.
+    ///
+    /// Like [build_query_string], this assumes that a table always gets the same factors in the same order.
+    fn add(
+        &mut self,
+        table: &'static str,
+        user_id: &str,
+        user_ip: &str,
+        factors: Vec<(&'static str, Factor)>,
+    ) {
+        let (names, values): (Vec<_>, Vec<_>) = factors.into_iter().unzip();
+        let dest = self.tables.entry(table).or_insert_with(|| TableBatch {
+            factor_names: names,
+            rows: Default::default(),
+        });
+        let users = dest.rows.entry(values).or_default();
+        users.ids.insert(user_id.to_string());
+        users.ips.insert(user_ip.to_string());
+    }
+
+    /// Add the rows of an item, as built by [batch_work_item].
+    fn add_item(&mut self, item: Batch) {
+        for (table, t) in item.tables.iter() {
+            let dest = self.tables.entry(table).or_insert_with(|| TableBatch {
+                factor_names: t.factor_names.clone(),
+                rows: Default::default(),
+            });
+            for (factors, users) in t.rows.iter() {
+                let dest_users = dest.rows.entry(factors.clone()).or_default();
+                dest_users.ids.extend(users.ids.iter().cloned());
+                dest_users.ips.extend(users.ips.iter().cloned());
+            }
+        }
+        self.items.push(item);
+    }
 }
 
 /// Build a query to insert into the hlls for one of our metrics tables.
@@ -154,212 +407,340 @@ impl WriterThread {
 /// If we need to optimize later, we can try to reliably pull these out into constants, but the sheer number of
 /// off-by-one errors that are possible there and our inability to tell that we made one other than having subtley wrong
 /// reporting makes that less than appealing.
+///
+/// The last two parameters are arrays of all the machine ids and ips for the row.
 fn build_query_string(table: &str, factors: &[&str]) -> String {
     let all_cols = factors
         .iter()
         .copied()
-        .chain((&["users_by_id", "users_by_ip"]).iter().copied());
+        .chain(["users_by_id", "users_by_ip"].iter().copied());
 
     let all_cols = itertools::join(all_cols, ",");
 
     let factor_params = itertools::join((1..=factors.len()).map(|x| format!("${}", x)), ",");
 
-    let user_id_param = format!("${}", factors.len() + 1);
-    let user_ip_param = format!("${}", factors.len() + 2);
+    let user_ids_param = format!("${}", factors.len() + 1);
+    let user_ips_param = format!("${}", factors.len() + 2);
 
     format!(
         r#"
 INSERT INTO {table} as t({all_cols}) VALUES
-({factor_params}, hll_empty() || hll_hash_text({user_id_param}), hll_empty() || hll_hash_text({user_ip_param}))
+({factor_params},
+    (SELECT hll_add_agg(hll_hash_text(x)) FROM unnest({user_ids_param}::TEXT[]) x),
+    (SELECT hll_add_agg(hll_hash_text(x)) FROM unnest({user_ips_param}::TEXT[]) x))
 ON CONFLICT ON CONSTRAINT {table}_upsert_constraint DO UPDATE SET
 (users_by_id, users_by_ip) = (
-    t.users_by_id || hll_hash_text({user_id_param}),
-    t.users_by_ip || hll_hash_text({user_ip_param})
+    t.users_by_id || EXCLUDED.users_by_id,
+    t.users_by_ip || EXCLUDED.users_by_ip
 )"#,
     )
 }
 
-/// Run an upsert query against a client.
-///
-/// This function assumes that the same table always gets the same query with the same factors in the same order, and
-/// exists to prevent off-by-ones and the like if we tried to write this at compile-time.  The HashMap is used to cache
-/// statements and is keyed by table name.
-async fn run_query(
+/// Get a statement from the cache, preparing the query built by `build` if it isn't there yet.
+async fn get_statement(
     client: &Client,
     cache: &StatementCache,
-    table_name: &'static str,
-    user_id: &str,
-    user_ip: &str,
-    factors: &[(&str, &(dyn tokio_postgres::types::ToSql + Sync))],
-) -> Result<()> {
-    let stmt: Arc<tokio_postgres::Statement> = {
-        // be careful here: without the let binding, the mutex lock in the if branch lasts for the whole outer
-        // expression.
-        if let Some(s) = {
-            let guard = cache.lock().unwrap();
-            let res: Option<Arc<Statement>> = guard.get(table_name).cloned();
-            res
-        } {
-            s
-        } else {
-            let fact_names: smallvec::SmallVec<[&str; 64]> = factors.iter().map(|x| x.0).collect();
-            let qstring = build_query_string(table_name, &fact_names[..]);
-            let stmt = Arc::new(client.prepare(&qstring).await?);
-            cache.lock().unwrap().insert(table_name, stmt.clone());
-            stmt
-        }
-    };
+    key: &'static str,
+    build: impl FnOnce() -> String,
+) -> Result<Arc<Statement>> {
+    // be careful here: this has to be its own statement, otherwise the mutex lock lasts for the whole if below and we
+    // deadlock when inserting.
+    let cached: Option<Arc<Statement>> = cache.lock().unwrap().get(key).cloned();
+    if let Some(s) = cached {
+        return Ok(s);
+    }
+
+    let stmt = Arc::new(client.prepare(&build()).await?);
+    cache.lock().unwrap().insert(key, stmt.clone());
+    Ok(stmt)
+}
+
+/// Write a batch in one transaction, so that either every table sees its items or none do.
+async fn write_batch(client: &mut Client, cache: &StatementCache, batch: &Batch) -> Result<()> {
+    // Statements are prepared on the connection, so they can be used by the transaction.  The cache is keyed by table
+    // name.
+    let mut statements = Vec::with_capacity(batch.tables.len());
+    for (table, t) in batch.tables.iter() {
+        let stmt = get_statement(client, cache, table, || {
+            build_query_string(table, &t.factor_names)
+        })
+        .await?;
+        statements.push(stmt);
+    }
 
-    let mut all_params: smallvec::SmallVec<[_; 64]> = factors.iter().map(|x| x.1).collect();
-    all_params.push(&user_id);
-    all_params.push(&user_ip);
+    let tx = client.transaction().await?;
 
-    client.execute(&*stmt, &all_params[..]).await?;
+    for ((table, t), stmt) in batch.tables.iter().zip(statements) {
+        let latency = metrics::WRITE_LATENCY.with_label_values(&[table]);
+        for (factors, users) in t.rows.iter() {
+            let ids: Vec<&str> = users.ids.iter().map(|x| x.as_str()).collect();
+            let ips: Vec<&str> = users.ips.iter().map(|x| x.as_str()).collect();
+
+            let mut params: smallvec::SmallVec<[&(dyn ToSql + Sync); 64]> =
+                factors.iter().map(|x| x as &(dyn ToSql + Sync)).collect();
+            params.push(&ids);
+            params.push(&ips);
+
+            let timer = latency.start_timer();
+            tx.execute(&*stmt, &params[..]).await?;
+            timer.observe_duration();
+        }
+    }
+
+    tx.commit().await?;
     Ok(())
 }
 
-async fn write_cpu_capabilities(
-    client: &Client,
-    cache: &StatementCache,
+fn add_cpu_capabilities(
+    batch: &mut Batch,
     context: &Context,
     work: &WorkItem,
-) -> Result<()> {
-    let c = &work.payload.simdsp.cpu_capabilities;
-    run_query(
-        client,
-        cache,
+    c: &CpuCapabilities,
+) {
+    batch.add(
         CPU_CAPABILITIES_TABLE,
         &work.payload.machine_id,
-        work.ip.as_deref().unwrap_or(UNKNOWN_IP),
-        &[
-            ("day", &context.day),
-            ("application", &context.application),
-            ("os", &context.os),
-            ("cpu_manufacturer", &context.cpu_manufacturer),
-            ("architecture", &context.architecture),
-            ("x86_sse2", &c.x86_sse2),
-            ("x86_sse3", &c.x86_sse3),
-            ("x86_ssse3", &c.x86_ssse3),
-            ("x86_sse4_1", &c.x86_sse4_1),
-            ("x86_fma3", &c.x86_fma3),
-            ("x86_avx", &c.x86_avx),
-            ("x86_avx2", &c.x86_avx2),
-            ("x86_avx512f", &c.x86_avx512f),
+        context.ip,
+        vec![
+            ("day", context.day.into()),
+            ("application", context.application.into()),
+            ("os", context.os.into()),
+            ("cpu_manufacturer", context.cpu_manufacturer.into()),
+            ("architecture", context.architecture.into()),
+            ("x86_sse2", c.x86_sse2.into()),
+            ("x86_sse3", c.x86_sse3.into()),
+            ("x86_ssse3", c.x86_ssse3.into()),
+            ("x86_sse4_1", c.x86_sse4_1.into()),
+            ("x86_popcnt_insn", c.x86_popcnt_insn.into()),
+            ("x86_fma3", c.x86_fma3.into()),
+            ("x86_fma4", c.x86_fma4.into()),
+            ("x86_xop", c.x86_xop.into()),
+            ("x86_avx", c.x86_avx.into()),
+            ("x86_avx2", c.x86_avx2.into()),
+            ("x86_avx512f", c.x86_avx512f.into()),
+            ("x86_avx512bw", c.x86_avx512bw.into()),
+            ("x86_avx512dq", c.x86_avx512dq.into()),
+            ("x86_avx512vl", c.x86_avx512vl.into()),
         ],
-    )
-    .await?;
-    Ok(())
+    );
 }
 
-async fn write_cpu_caches(
-    client: &Client,
-    cache: &StatementCache,
+fn add_cpu_capabilities_arm(
+    batch: &mut Batch,
     context: &Context,
     work: &WorkItem,
-) -> Result<()> {
-    use crate::anonymization::round_cache;
-
-    fn anon(x: u64) -> i64 {
-        round_cache(x) as i64
-    }
-
-    let c = &work.payload.simdsp.cache_info;
-    run_query(
-        client,
-        cache,
-        CPU_CACHES_TABLE,
+    c: &ArmCapabilities,
+) {
+    batch.add(
+        CPU_CAPABILITIES_ARM_TABLE,
         &work.payload.machine_id,
-        work.ip.as_deref().unwrap_or(UNKNOWN_IP),
-        &[
-            ("day", &context.day),
-            ("application", &context.application),
-            ("l1i", &anon(c.l1i)),
-            ("l1d", &anon(c.l1d)),
-            ("l1u", &anon(c.l1u)),
-            ("l2i", &anon(c.l2i)),
-            ("l2d", &anon(c.l2d)),
-            ("l2u", &anon(c.l2u)),
-            ("l3i", &anon(c.l3i)),
-            ("l3d", &anon(c.l3d)),
-            ("l3u", &anon(c.l3u)),
+        context.ip,
+        vec![
+            ("day", context.day.into()),
+            ("application", context.application.into()),
+            ("os", context.os.into()),
+            ("cpu_manufacturer", context.cpu_manufacturer.into()),
+            ("architecture", context.architecture.into()),
+            ("neon", c.neon.into()),
+            ("asimd_dotprod", c.asimd_dotprod.into()),
+            ("fp16", c.fp16.into()),
+            ("sve", c.sve.into()),
+            ("sve2", c.sve2.into()),
+            (
+                "sve_vector_length",
+                (round_sve_vector_length(c.sve_vector_length) as i64).into(),
+            ),
         ],
-    )
-    .await?;
-    Ok(())
+    );
 }
 
-async fn write_memory(
-    client: &Client,
-    cache: &StatementCache,
+fn add_cpu_caches(
+    batch: &mut Batch,
     context: &Context,
     work: &WorkItem,
-) -> Result<()> {
-    use crate::anonymization::round_mem;
+    c: &CacheInfo,
+    bins: &Bins,
+) {
+    let anon = |x: u64| -> Factor { (bins.round_cache(x) as i64).into() };
 
-    fn anon(x: u64) -> i64 {
-        round_mem(x) as i64
-    }
+    batch.add(
+        CPU_CACHES_TABLE,
+        &work.payload.machine_id,
+        context.ip,
+        vec![
+            ("day", context.day.into()),
+            ("application", context.application.into()),
+            ("bins_version", bins.cache.version.into()),
+            ("l1i", anon(c.l1i)),
+            ("l1d", anon(c.l1d)),
+            ("l1u", anon(c.l1u)),
+            ("l2i", anon(c.l2i)),
+            ("l2d", anon(c.l2d)),
+            ("l2u", anon(c.l2u)),
+            ("l3i", anon(c.l3i)),
+            ("l3d", anon(c.l3d)),
+            ("l3u", anon(c.l3u)),
+        ],
+    );
+}
 
-    run_query(
-        client,
-        cache,
+fn add_memory(batch: &mut Batch, context: &Context, work: &WorkItem, memory: &Memory, bins: &Bins) {
+    batch.add(
         MEMORY_TABLE,
         &work.payload.machine_id,
-        work.ip.as_deref().unwrap_or(UNKNOWN_IP),
-        &[
-            ("day", &context.day),
-            ("application", &context.application),
-            ("total_memory", &anon(work.payload.memory.total)),
+        context.ip,
+        vec![
+            ("day", context.day.into()),
+            ("application", context.application.into()),
+            ("bins_version", bins.memory.version.into()),
+            ("total_memory", (bins.round_mem(memory.total) as i64).into()),
         ],
-    )
-    .await?;
-    Ok(())
+    );
 }
 
-async fn write_cf_country(
-    client: &Client,
-    cache: &StatementCache,
+fn add_cf_country(
+    batch: &mut Batch,
     context: &Context,
     work: &WorkItem,
-) -> Result<()> {
-    let country = work.country.as_deref().unwrap_or(UNKNOWN_COUNTRY);
-
-    // If we somehow get a non-2-character country code here, something is wrong.
-    if country.len() != 2 {
-        anyhow::bail!("Got country code {} which is invalid", country);
-    }
-
-    run_query(
-        client,
-        cache,
+    country: &str,
+    source: &'static str,
+) {
+    batch.add(
         CF_COUNTRY_TABLE,
         &work.payload.machine_id,
-        work.ip.as_deref().unwrap_or(UNKNOWN_IP),
-        &[
-            ("day", &context.day),
-            ("application", &context.application),
-            ("country", &country),
+        context.ip,
+        vec![
+            ("day", context.day.into()),
+            ("application", context.application.into()),
+            ("source", source.into()),
+            ("country", country.into()),
         ],
-    )
+    );
+}
+
+/// Count a dimension value we don't know about so that an admin can decide whether to add it.
+async fn record_pending_dimension(
+    client: &Client,
+    cache: &StatementCache,
+    dimension: Dimension,
+    value: &str,
+) -> Result<()> {
+    let stmt = get_statement(client, cache, PENDING_DIMENSION_TABLE, || {
+        format!(
+            r#"
+INSERT INTO {PENDING_DIMENSION_TABLE} AS t(dimension, name, times_seen, first_seen, last_seen) VALUES
+($1, $2, 1, now(), now())
+ON CONFLICT (dimension, name) DO UPDATE SET
+(times_seen, last_seen) = (t.times_seen + 1, now())"#
+        )
+    })
     .await?;
+
+    client
+        .execute(&*stmt, &[&dimension.table_name(), &value])
+        .await?;
     Ok(())
 }
 
-async fn write_work_item(
+/// Get the uuid for a dimension value, falling back to unknown.
+///
+/// If discovery is on and the value is allowed, also record it as pending.
+async fn resolve_dimension(
     writer: &WriterThread,
     client: &Client,
     cache: &StatementCache,
+    uuid_cache: &UuidCache,
+    dimension: Dimension,
+    value: &str,
+) -> Uuid {
+    if let Some(id) = uuid_cache.find(dimension, value) {
+        return id;
+    }
+
+    if let Some(allowlist) = writer.dimension_allowlist.as_ref() {
+        if allowlist.is_match(value) {
+            if let Err(e) = record_pending_dimension(client, cache, dimension, value).await {
+                only_every::only_every!(
+                    Duration::from_secs(30),
+                    log::warn!("Unable to record pending dimension value: {:?}", e)
+                );
+            }
+        }
+    }
+
+    uuid_cache.unknown(dimension)
+}
+
+/// Work out an item's country, and where we got it from.  `geoip` looks up the country of an IP.
+fn resolve_country(
     work: &WorkItem,
-) -> Result<()> {
-    let uc = &writer.uuid_cache;
+    geoip: impl Fn(IpAddr) -> Option<String>,
+    unknown_country: &str,
+) -> (String, &'static str) {
+    if let Some(c) = &work.country {
+        return (c.clone(), COUNTRY_FROM_HEADER);
+    }
+
+    let from_geoip = work.ip.as_deref().and_then(|ip| geoip(ip.parse().ok()?));
+    match from_geoip {
+        Some(c) => (c, COUNTRY_FROM_GEOIP),
+        None => (unknown_country.to_string(), COUNTRY_UNKNOWN),
+    }
+}
+
+/// Build the rows of one item, to be added to a batch with [Batch::add_item], or fail if it's invalid.
+async fn batch_work_item(
+    writer: &WriterThread,
+    client: &Client,
+    cache: &StatementCache,
+    work: &WorkItem,
+) -> Result<Batch> {
+    let (country, country_source) = resolve_country(
+        work,
+        |ip| writer.geoip.as_ref()?.country(ip),
+        &writer.unknown_country,
+    );
+
+    // If we somehow get a non-2-character country code here, something is wrong.
+    if country.len() != 2 {
+        anyhow::bail!("Got country code {} which is invalid", country);
+    }
+
+    // Grab the cache once so that a reload partway through can't give us a mix of old and new uuids.
+    let uc = writer.uuid_cache.get();
 
     // Note that application is validated in send.
-    let os = uc.get_os(&work.payload.os);
-    let architecture = uc.get_architecture(&work.payload.simdsp.cpu_architecture);
-    let cpu_manufacturer = uc.get_cpu_manufacturer(&work.payload.simdsp.cpu_manufacturer);
+    let os = resolve_dimension(writer, client, cache, &uc, Dimension::Os, &work.payload.os).await;
+    let (architecture, cpu_manufacturer) = match &work.payload.cpu {
+        Some(cpu) => (
+            resolve_dimension(
+                writer,
+                client,
+                cache,
+                &uc,
+                Dimension::CpuArchitecture,
+                &cpu.architecture,
+            )
+            .await,
+            resolve_dimension(
+                writer,
+                client,
+                cache,
+                &uc,
+                Dimension::CpuManufacturer,
+                &cpu.manufacturer,
+            )
+            .await,
+        ),
+        None => (
+            uc.unknown(Dimension::CpuArchitecture),
+            uc.unknown(Dimension::CpuManufacturer),
+        ),
+    };
     let day = work.received_at.duration_trunc(CDuration::days(1))?;
 
     let context = Context {
+        ip: work.ip.as_deref().unwrap_or(&writer.unknown_ip),
         application: work.token,
         os,
         architecture,
@@ -367,93 +748,418 @@ async fn write_work_item(
         day,
     };
 
-    let (caches, caps, mem, country) = tokio::join!(
-        write_cpu_caches(client, cache, &context, work),
-        write_cpu_capabilities(client, cache, &context, work),
-        write_memory(client, cache, &context, work),
-        write_cf_country(client, cache, &context, work)
-    );
+    let mut batch = Batch::default();
 
-    if let Err(ref e) = caps {
-        log::error!("Unable to write CPU capabilities: {:?}", e);
-    }
-    if let Err(ref e) = caches {
-        log::error!("Unable to write cache info: {:?}", e);
+    // Sections the client left out are left out of their tables too, rather than being counted as zeros.
+    if let Some(cpu) = &work.payload.cpu {
+        if let Some(c) = &cpu.capabilities {
+            add_cpu_capabilities(&mut batch, &context, work, c);
+        }
+        if let Some(c) = &cpu.arm_capabilities {
+            add_cpu_capabilities_arm(&mut batch, &context, work, c);
+        }
+        if let Some(c) = &cpu.caches {
+            add_cpu_caches(&mut batch, &context, work, c, &writer.bins);
+        }
     }
-    if let Err(ref e) = mem {
-        log::error!("Unable to write memory info: {:?}", e);
+    if let Some(m) = &work.payload.memory {
+        add_memory(&mut batch, &context, work, m, &writer.bins);
     }
-    if let Err(ref e) = country {
-        log::error!("Unable to write country info: {:?}", e);
+    add_cf_country(&mut batch, &context, work, &country, country_source);
+    Ok(batch)
+}
+
+/// Wait for an item, then batch it with whatever else is already queued, up to [MAX_BATCH_ITEMS].
+///
+/// When the queue is quiet this writes items one at a time as before, and batches grow as it backs up.  Returns `None`
+/// once we're shutting down and the queue is empty.
+async fn next_batch(
+    writer: &WriterThread,
+    client: &Client,
+    cache: &StatementCache,
+) -> Option<Batch> {
+    let mut batch = Batch::default();
+    let mut next = Some(writer.receiver.recv().await.ok()?);
+    let mut taken = 0;
+
+    while let Some(item) = next {
+        taken += 1;
+        metrics::ITEMS_TAKEN.inc();
+        match batch_work_item(writer, client, cache, &item).await {
+            Ok(i) => batch.add_item(i),
+            Err(e) => {
+                metrics::ITEMS_FAILED.inc();
+                only_every::only_every!(
+                    Duration::from_secs(30),
+                    log::warn!("Unable to write work item because {:?}", e)
+                );
+            }
+        }
+
+        if taken >= MAX_BATCH_ITEMS {
+            break;
+        }
+        next = writer.receiver.try_recv().ok();
     }
 
-    // Report the first of these which failed as the error.
-    caps?;
-    caches?;
-    mem?;
-    country?;
-    Ok(())
+    Some(batch)
+}
+
+/// Did this error happen because the database connection went away?
+fn connection_lost(client: &Client, error: &anyhow::Error) -> bool {
+    client.is_closed()
+        || error
+            .downcast_ref::<tokio_postgres::Error>()
+            .is_some_and(|e| e.is_closed())
 }
 
+/// Write batches from the queue until the connection fails, or until we're shutting down and the queue is empty.
+///
+/// If a batch fails because the connection went away, it is left in `retry` so that it can be written again once we
+/// reconnect.  Batches are written in a transaction, so none of it was written the first time.
 async fn writer_task_fallible(
-    writer: Arc<WriterThread>,
-    client: Client,
-    connection_task: tokio::task::JoinHandle<std::result::Result<(), tokio_postgres::Error>>,
+    writer: &WriterThread,
+    client: &mut Client,
+    retry: &mut Option<Batch>,
 ) -> Result<()> {
-    let mut statement_cache = Default::default();
-    tokio::pin!(connection_task);
+    // Prepared statements belong to a connection, so each connection we get from the pool needs a fresh cache.
+    let statement_cache = Default::default();
 
     loop {
-        select! {
-            Ok(r) = writer.receiver.recv() => {
-                if let Err(e) = write_work_item(&*writer, &client, &mut statement_cache, &r).await {
-                    only_every::only_every!(
-                        Duration::from_secs(30),
-                        log::warn!("Unable to write work item because {:?}", e)
-                    );
-                }
+        let batch = match retry.take() {
+            Some(b) => b,
+            None => match next_batch(writer, client, &statement_cache).await {
+                Some(b) => b,
+                None => return Ok(()),
             },
-            x = &mut connection_task => {
-                match x? {
-                    Ok(_) => anyhow::bail!("The database client exited without error, but should have remained up forever"),
-                    Err(e) => anyhow::bail!("Database error! P{:?}", e),
-                }
+        };
+
+        write_or_split(client, &statement_cache, batch, retry).await?;
+    }
+}
+
+/// Write a batch.  If it fails for any reason but the connection going away, write its items one at a time instead, so
+/// that one bad item doesn't lose the rest.
+///
+/// If the connection goes away, whatever wasn't written is left in `retry` and the error is returned.
+async fn write_or_split(
+    client: &mut Client,
+    cache: &StatementCache,
+    batch: Batch,
+    retry: &mut Option<Batch>,
+) -> Result<()> {
+    match write_batch(client, cache, &batch).await {
+        Ok(()) => {
+            metrics::ITEMS_WRITTEN.inc_by(batch.items.len() as u64);
+            return Ok(());
+        }
+        Err(e) if connection_lost(client, &e) => {
+            *retry = Some(batch);
+            return Err(e.context("Lost the database connection while writing"));
+        }
+        Err(e) if batch.items.len() > 1 => {
+            only_every::only_every!(
+                Duration::from_secs(30),
+                log::warn!(
+                    "Unable to write a batch of {} items, writing them one at a time: {:?}",
+                    batch.items.len(),
+                    e
+                )
+            );
+        }
+        Err(e) => {
+            metrics::ITEMS_FAILED.inc_by(batch.items.len() as u64);
+            only_every::only_every!(
+                Duration::from_secs(30),
+                log::warn!("Unable to write work item because {:?}", e)
+            );
+            return Ok(());
+        }
+    }
+
+    let mut items: std::collections::VecDeque<Batch> = batch.items.into();
+    while let Some(item) = items.pop_front() {
+        match write_batch(client, cache, &item).await {
+            Ok(()) => metrics::ITEMS_WRITTEN.inc(),
+            Err(e) if connection_lost(client, &e) => {
+                let mut rest = Batch::default();
+                rest.add_item(item);
+                items.into_iter().for_each(|i| rest.add_item(i));
+                *retry = Some(rest);
+                return Err(e.context("Lost the database connection while writing"));
+            }
+            Err(e) => {
+                metrics::ITEMS_FAILED.inc();
+                only_every::only_every!(
+                    Duration::from_secs(30),
+                    log::warn!("Unable to write work item because {:?}", e)
+                );
             }
         }
     }
+    Ok(())
 }
 
-async fn writer_task(
-    writer: Arc<WriterThread>,
-    client: Client,
-    connection_task: tokio::task::JoinHandle<std::result::Result<(), tokio_postgres::Error>>,
-) {
-    log::info!("Writer running");
+/// One of the writer's workers.  Each holds a connection from the pool for as long as the connection stays up.
+async fn writer_task(writer: Arc<WriterThread>, pool: Pool, worker: usize) {
+    let _alive = AliveGuard(&writer.workers_alive);
+    log::info!("Writer {} running", worker);
 
-    // If the writer fails, crash and come back up.  This is Rust, we'll be back in a few seconds or less, and we don't
-    // need 100% uptime.  Some thrashing is ok.
-    writer_task_fallible(writer, client, connection_task)
-        .await
-        .expect("The writer crashed");
+    // Items keep queueing while we're disconnected, and are written once we're back.  If we're down long enough for the
+    // queue to fill, submissions are turned away until there's room.
+    let mut retry = None;
+    let mut delay = MIN_RECONNECT_DELAY;
+    let mut reconnecting = false;
+
+    loop {
+        let mut client = match pool.get().await {
+            Ok(c) => {
+                // We only come back around for another connection after losing one, so only the first isn't a
+                // reconnect.
+                if reconnecting {
+                    metrics::DB_RECONNECTS.inc();
+                }
+                delay = MIN_RECONNECT_DELAY;
+                c
+            }
+            Err(e) => {
+                log::error!(
+                    "Writer {} unable to get a database connection, retrying in {:?}: {:?}",
+                    worker,
+                    delay,
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                continue;
+            }
+        };
+
+        // The pool notices the connection is closed when it gets this one back, and opens a new one next time.
+        match writer_task_fallible(&writer, &mut client, &mut retry).await {
+            Ok(()) => {
+                log::info!("Writer {} finished", worker);
+                return;
+            }
+            Err(e) => {
+                reconnecting = true;
+                log::error!(
+                    "Writer {} lost its database connection with {} items queued: {:?}",
+                    worker,
+                    writer.receiver.len(),
+                    e
+                );
+            }
+        }
+    }
 }
 
-pub async fn spawn(db_url: &str) -> Result<Arc<WriterThread>> {
-    let (client, connection) = tokio_postgres::connect(db_url, tokio_postgres::NoTls).await?;
-    let connection_task = tokio::spawn(connection);
+pub async fn spawn(db_url: &str, options: WriterOptions) -> Result<Arc<WriterThread>> {
+    if options.workers == 0 {
+        anyhow::bail!("The writer needs at least one worker");
+    }
 
-    let uuid_cache = UuidCache::load(&client).await?;
+    let manager = Manager::from_config(
+        db_url.parse()?,
+        tokio_postgres::NoTls,
+        ManagerConfig {
+            recycling_method: RecyclingMethod::Fast,
+        },
+    );
+    // Workers hold on to their connections, so leave one more for readiness checks.
+    let pool = Pool::builder(manager)
+        .max_size(options.workers + 1)
+        .build()?;
+
+    let uuid_cache = UuidCache::load(&*pool.get().await?).await?;
     log::info!("Uuid cache is: {:?}", uuid_cache);
+    let uuid_cache = Arc::new(SharedUuidCache::new(uuid_cache));
 
-    let (sender, receiver) = bounded(MAX_OUTSTANDING_ITEMS);
+    let bins = crate::anonymization::load(&mut *pool.get().await?, &options.bins).await?;
+    log::info!(
+        "Using version {} of the cache bins and version {} of the memory bins",
+        bins.cache.version,
+        bins.memory.version
+    );
+    if let Err(e) = crate::anonymization::check_memory_population(
+        &*pool.get().await?,
+        &bins.memory,
+        options.bins.min_bin_population,
+    )
+    .await
+    {
+        log::warn!("Unable to count the machines in each memory bin: {:?}", e);
+    }
+    tokio::spawn(crate::uuid_cache::refresh_task(
+        uuid_cache.clone(),
+        db_url.to_string(),
+        options.uuid_cache_refresh,
+    ));
+
+    let (sender, receiver) = bounded(options.queue_size);
     let thread = Arc::new(WriterThread {
         sender,
         receiver,
         uuid_cache,
+        dimension_allowlist: options.dimension_allowlist,
+        workers: Default::default(),
+        workers_alive: AtomicUsize::new(options.workers),
+        pool: pool.clone(),
+        unknown_ip: options.unknown_ip,
+        unknown_country: options.unknown_country,
+        bins,
+        geoip: options.geoip,
     });
 
-    let thread_cloned = thread.clone();
-
-    tokio::spawn(writer_task(thread_cloned, client, connection_task));
+    let workers = (0..options.workers)
+        .map(|worker| tokio::spawn(writer_task(thread.clone(), pool.clone(), worker)))
+        .collect();
+    *thread.workers.lock().unwrap() = workers;
 
     Ok(thread)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn work_item(country: Option<&str>, ip: Option<&str>, token: Uuid) -> WorkItem {
+        WorkItem {
+            country: country.map(|x| x.to_string()),
+            ip: ip.map(|x| x.to_string()),
+            payload: PayloadV2 {
+                version: Default::default(),
+                machine_id: "abc".to_string(),
+                os: "linux".to_string(),
+                cpu: None,
+                memory: None,
+            },
+            received_at: Utc::now(),
+            token,
+        }
+    }
+
+    #[test]
+    fn test_ready_queue_limit() {
+        assert_eq!(ready_queue_limit(1), 1);
+        assert_eq!(ready_queue_limit(2), 1);
+        assert_eq!(ready_queue_limit(10), 9);
+        assert_eq!(ready_queue_limit(1000), 900);
+    }
+
+    #[test]
+    fn test_resolve_country() {
+        let geoip = |ip: IpAddr| {
+            (ip == "198.51.100.1".parse::<IpAddr>().unwrap()).then(|| "NZ".to_string())
+        };
+        let no_geoip = |_| None;
+        let resolve = |country, ip, geoip: &dyn Fn(IpAddr) -> Option<String>| {
+            resolve_country(&work_item(country, ip, Uuid::nil()), geoip, "XX")
+        };
+
+        // The header wins, even if GeoIP would say otherwise.
+        assert_eq!(
+            resolve(Some("US"), Some("198.51.100.1"), &geoip),
+            ("US".to_string(), COUNTRY_FROM_HEADER)
+        );
+        assert_eq!(
+            resolve(Some("US"), None, &no_geoip),
+            ("US".to_string(), COUNTRY_FROM_HEADER)
+        );
+
+        assert_eq!(
+            resolve(None, Some("198.51.100.1"), &geoip),
+            ("NZ".to_string(), COUNTRY_FROM_GEOIP)
+        );
+
+        // GeoIP doesn't know the address, there's no address, or there's no GeoIP.
+        for (ip, lookup) in [
+            (
+                Some("203.0.113.9"),
+                &geoip as &dyn Fn(IpAddr) -> Option<String>,
+            ),
+            (Some("not an ip"), &geoip),
+            (None, &geoip),
+            (Some("198.51.100.1"), &no_geoip),
+        ] {
+            assert_eq!(
+                resolve(None, ip, lookup),
+                ("XX".to_string(), COUNTRY_UNKNOWN),
+                "{:?}",
+                ip
+            );
+        }
+
+        // These end up in cf_country.source, so changing them splits the data.
+        assert_eq!(
+            [COUNTRY_FROM_HEADER, COUNTRY_FROM_GEOIP, COUNTRY_UNKNOWN],
+            ["cloudflare", "geoip", "unknown"]
+        );
+    }
+
+    /// Needs a database the server has migrated, in `HWSURVEY_TEST_DATABASE_URL`.  Adds an application and writes to
+    /// it.
+    #[tokio::test]
+    #[ignore = "needs a database; set HWSURVEY_TEST_DATABASE_URL"]
+    async fn test_bad_item_only_loses_itself() {
+        let url = std::env::var("HWSURVEY_TEST_DATABASE_URL").unwrap();
+        let token = Uuid::new_v4();
+        crate::db::connect(&url, "tests")
+            .await
+            .unwrap()
+            .execute(
+                "INSERT INTO application(id, name) VALUES($1, $2)",
+                &[&token, &format!("test {}", token)],
+            )
+            .await
+            .unwrap();
+
+        let writer = spawn(
+            &url,
+            WriterOptions {
+                uuid_cache_refresh: Duration::from_secs(300),
+                dimension_allowlist: None,
+                workers: 1,
+                queue_size: 10,
+                unknown_ip: "123.123.123.123".to_string(),
+                unknown_country: "XX".to_string(),
+                bins: Default::default(),
+                geoip: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        // Postgres won't store a NUL in text, so the third item fails when it's written rather than when it's batched.
+        let mut batch = Batch::default();
+        let mut client = writer.pool.get().await.unwrap();
+        let cache = StatementCache::default();
+        for (i, country) in ["AA", "AB", "\0X", "AC", "AD"].into_iter().enumerate() {
+            let work = work_item(Some(country), Some(&format!("10.0.0.{}", i)), token);
+            batch.add_item(
+                batch_work_item(&writer, &client, &cache, &work)
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert!(write_batch(&mut client, &cache, &batch).await.is_err());
+
+        let mut retry = None;
+        write_or_split(&mut client, &cache, batch, &mut retry)
+            .await
+            .unwrap();
+        assert!(retry.is_none());
+
+        let countries = client
+            .query(
+                "SELECT country FROM cf_country WHERE application = $1 ORDER BY country",
+                &[&token],
+            )
+            .await
+            .unwrap()
+            .iter()
+            .map(|r| r.get::<_, String>(0))
+            .collect::<Vec<_>>();
+        assert_eq!(countries, vec!["AA", "AB", "AC", "AD"]);
+    }
+}
@@ -3,5 +3,6 @@ set -ex
 cd /backend/server
 # The database can take a minute to come up.
 sleep 5
-refinery migrate -e DATABASE_URL  -p ./migrations
-cargo run -- --address 0.0.0.0 --port 10000
+# The server applies its own migrations at startup.
+# exec so that the server gets the SIGTERM when the container is stopped.
+exec cargo run -- --address 0.0.0.0 --port 10000